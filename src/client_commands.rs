@@ -1,10 +1,20 @@
-use crate::{Room, UserInfo};
+use crate::{
+    config, display_name, format_duration, format_timestamp, parse_date, Away, Lifecycle, Profile, Role, Room,
+    UserInfo, Visibility,
+};
 use crate::color_codes;
+use crate::latency;
+use crate::history::{self, Conversation, History, SearchQuery};
+use crate::uploads::{self, SharedFile, Upload, UploadTarget, Uploads};
 use std::sync::Arc;
+use std::time::{Instant, SystemTime};
 use tokio::io::AsyncWriteExt;
 use tokio::net::tcp::WriteHalf;
 use tokio::sync::{broadcast, Mutex as TokioMutex};
 
+// Replies that fail to send are ignored here, a peer that is gone makes the session's next
+// read fail and the session loop cleans up after it.
+
 
 
 pub(crate) async fn handle_create_room_command(
@@ -13,29 +23,57 @@ pub(crate) async fn handle_create_room_command(
     username: &str,
     rooms: Arc<TokioMutex<Vec<Room>>>,
 ) {
-    let mut parts = line.trim().split_whitespace();
+    let mut parts = line.split_whitespace();
     parts.next(); // skip /create
     if let Some(room_name) = parts.next() {
-        if room_name == "glb" || room_name == "adm" {
-            write_half
-                .write_all(format!("\n{}[i] Room name 'glb' and 'adm' are reserved{}\n\n", color_codes::RED, color_codes::RESET).as_bytes())
-                .await
-                .unwrap();
+        // options can be given in any order after the room name
+        let mut visibility = Visibility::Public;
+        let mut lifecycle = None;
+        for option in parts {
+            if let Some(parsed) = Visibility::parse(option) {
+                visibility = parsed;
+            } else if let Some(parsed) = Lifecycle::parse(option) {
+                lifecycle = Some(parsed);
+            } else {
+                let _ = write_half
+                    .write_all(format!("\n{}[i] Unknown room option {}, see /help /create_room{}\n\n", color_codes::RED, option, color_codes::RESET).as_bytes())
+                    .await;
+                return;
+            }
+        }
+        // room lines start with [room_name], a bracket in the name could make them look like [PM] or [event] lines
+        if room_name.contains(['[', ']']) || room_name.contains(char::is_control) {
+            let _ = write_half
+                .write_all(format!("\n{}[i] Room names cannot contain brackets{}\n\n", color_codes::RED, color_codes::RESET).as_bytes())
+                .await;
+        } else if config::RESERVED_ROOM_NAMES.contains(&room_name) {
+            let _ = write_half
+                .write_all(format!("\n{}[i] Room names {} are reserved{}\n\n", color_codes::RED, config::RESERVED_ROOM_NAMES.join(", "), color_codes::RESET).as_bytes())
+                .await;
         } else {
-            let room = Room {
-                name: room_name.to_string(),
-                users: vec![],
-            };
             let mut rooms_guard = rooms.lock().await;
+            // the same answer for every existing room so unlisted and invite only rooms aren't given away
+            if rooms_guard.iter().any(|r| r.name == room_name) {
+                drop(rooms_guard);
+                let _ = write_half
+                    .write_all(format!("\n{}[i] Room name {} is unavailable{}\n\n", color_codes::RED, room_name, color_codes::RESET).as_bytes())
+                    .await;
+                return;
+            }
+            let mut room = Room::new(room_name, username);
+            room.visibility = visibility;
+            if let Some(lifecycle) = lifecycle {
+                room.lifecycle = lifecycle;
+            }
+            let lifecycle = room.lifecycle.describe();
             rooms_guard.push(room);
             drop(rooms_guard);
-            println!("Room {} created by {}", room_name, username);
+            println!("Room {} created by {} ({}, {})", room_name, username, visibility.label(), lifecycle);
         }
     } else {
-        write_half
+        let _ = write_half
             .write_all(format!("\n{}[i] No room name provided{}\n\n",color_codes::YELLOW, color_codes::RESET).as_bytes())
-            .await
-            .unwrap();
+            .await;
     }
 }
 
@@ -46,63 +84,182 @@ pub(crate) async fn handle_join_room_command(
     addr: std::net::SocketAddr,
     rooms: Arc<TokioMutex<Vec<Room>>>,
     users: Arc<TokioMutex<Vec<UserInfo>>>,
+    history: Arc<TokioMutex<History>>,
 ) {
-    let mut parts = line.trim().split_whitespace();
+    let mut parts = line.split_whitespace();
     parts.next(); // skip /join
     if let Some(room_name) = parts.next() {
         let mut rooms_guard = rooms.lock().await;
-        let room = rooms_guard.iter_mut().find(|r| r.name == room_name);
+        // invite only rooms look the same as missing ones to users who can't join them
+        let room = rooms_guard
+            .iter_mut()
+            .find(|r| r.name == room_name && (r.is_member(username) || r.is_joinable_by(username)));
         if let Some(room) = room {
-            room.users.push(UserInfo {
-                username: username.parse().unwrap(),
-                addr,
-                rooms: vec![room_name.to_string()],
-            });
-            // add room to user's list of rooms
-            let mut users_guard = users.lock().await;
-            let user = users_guard.iter_mut().find(|u| u.username == username);
-            if let Some(user) = user {
-                user.rooms.push(room_name.to_string());
+            if room.is_member(username) {
+                let _ = write_half
+                    .write_all(b"[i] You are already a member of this room\n")
+                    .await;
+                return;
             }
-            drop(users_guard);
+            if room.is_full() {
+                let full_message = room_full_message(room);
+                drop(rooms_guard);
+                let _ = write_half.write_all(full_message.as_bytes()).await;
+                return;
+            }
+            let banner = add_member(room, username, addr, users.clone()).await;
+            let pins = pinned_messages(room, history).await;
+            drop(rooms_guard);
             println!("User {} joined room {}", username, room_name);
             // write to user that they joined the room
-            write_half
-                .write_all(format!("You joined room {}\n", room_name).as_bytes())
-                .await
-                .unwrap();
+            let _ = write_half
+                .write_all(format!("You joined room {}\n{}{}", room_name, banner, pins).as_bytes())
+                .await;
         } else {
             println!("Room {} does not exist", room_name);
             // write to user that the room does not exist
-            write_half
+            let _ = write_half
                 .write_all(format!("\n{}Room {} does not exist{}\n\n",color_codes::RED, room_name, color_codes::RESET).as_bytes())
-                .await
-                .unwrap();
+                .await;
         }
     } else {
-        write_half
+        let _ = write_half
             .write_all(b"No room name provided\n")
-            .await
-            .unwrap();
+            .await;
+    }
+}
+
+// tells a user who can't get into a full room how to wait for a spot
+fn room_full_message(room: &Room) -> String {
+    format!(
+        "\n{}[i] Room {} is full ({} members). Use /waitlist {} to get the next free spot{}\n\n",
+        color_codes::RED,
+        room.name,
+        room.users.len(),
+        room.name,
+        color_codes::RESET
+    )
+}
+
+// give the spot freed up in a room to the first user on its waitlist who is still online
+pub(crate) async fn admit_from_waitlist(
+    room: &mut Room,
+    tx: broadcast::Sender<(String, std::net::SocketAddr)>,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+) {
+    while !room.is_full() && !room.waitlist.is_empty() {
+        let next = room.waitlist.remove(0);
+        let users_guard = users.lock().await;
+        let next_addr = users_guard.iter().find(|u| u.username == next).map(|u| u.addr);
+        drop(users_guard);
+        let Some(next_addr) = next_addr else {
+            continue;
+        };
+
+        let banner = add_member(room, &next, next_addr, users.clone()).await;
+        println!("User {} joined room {} from the waitlist", next, room.name);
+        let notice = format!("[notice] A spot opened up, you joined room {}\n{}", room.name, banner);
+        tx.send((notice, next_addr)).unwrap();
+    }
+}
+
+// tell the members of a deleted room, take it off their room lists and forget its messages and files
+pub(crate) async fn close_room(
+    room: Room,
+    reason: &str,
+    tx: broadcast::Sender<(String, std::net::SocketAddr)>,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+    history: Arc<TokioMutex<History>>,
+    uploads: Arc<TokioMutex<Uploads>>,
+) {
+    let mut users_guard = users.lock().await;
+    for user in users_guard.iter_mut() {
+        if !user.rooms.contains(&room.name) {
+            continue;
+        }
+        user.rooms.retain(|r| r != &room.name);
+        let notice = format!("[notice] Room {} was deleted because {}\n", room.name, reason);
+        tx.send((notice, user.addr)).unwrap();
+    }
+    drop(users_guard);
+
+    history.lock().await.remove_room(&room.name);
+    let removed = uploads.lock().await.remove_room(&room.name);
+    for id in removed {
+        if let Err(e) = tokio::fs::remove_file(uploads::path(&id)).await {
+            println!("Failed to remove upload {}: {}", id, e);
+        }
+    }
+}
+
+// add a user to a room and the room to the user's list, returns the room's join banner
+async fn add_member(
+    room: &mut Room,
+    username: &str,
+    addr: std::net::SocketAddr,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+) -> String {
+    let mut member = UserInfo::new(username, addr);
+    member.rooms.push(room.name.clone());
+    room.users.push(member);
+    // rejoining keeps the old marker so messages missed in between show up as unread
+    if room.read_marker(username).is_none() {
+        room.mark_read(username, SystemTime::now());
+    }
+    room.invited.retain(|u| u != username);
+    room.waitlist.retain(|u| u != username);
+    room.last_activity = Instant::now();
+
+    // add room to user's list of rooms
+    let mut users_guard = users.lock().await;
+    let user = users_guard.iter_mut().find(|u| u.username == username);
+    if let Some(user) = user {
+        user.rooms.push(room.name.clone());
+    }
+    drop(users_guard);
+
+    room.banner()
+}
+
+// the pinned part of the join banner, empty when nothing is pinned
+async fn pinned_messages(room: &Room, history: Arc<TokioMutex<History>>) -> String {
+    let history_guard = history.lock().await;
+    if room.pinned.is_empty() {
+        return String::new();
+    }
+    let mut pins = "Pinned:\n".to_string();
+    for id in &room.pinned {
+        match history_guard.find(id) {
+            Some(message) => pins.push_str(&format!("  {}\n", message.render())),
+            None => pins.push_str(&format!("  #{} [message no longer in history]\n", id)),
+        }
     }
+    pins
 }
 
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn handle_leave_room_command(
     write_half: &mut WriteHalf<'_>,
     line: &str,
     username: &str,
+    tx: broadcast::Sender<(String, std::net::SocketAddr)>,
     rooms: Arc<TokioMutex<Vec<Room>>>,
     users: Arc<TokioMutex<Vec<UserInfo>>>,
+    history: Arc<TokioMutex<History>>,
+    uploads: Arc<TokioMutex<Uploads>>,
 ) {
-    let mut parts = line.trim().split_whitespace();
+    let mut parts = line.split_whitespace();
     parts.next(); // skip /leave
     if let Some(room_name) = parts.next() {
         let mut rooms_guard = rooms.lock().await;
-        let room = rooms_guard.iter_mut().find(|r| r.name == room_name);
+        let room = rooms_guard
+            .iter_mut()
+            .find(|r| r.name == room_name && !r.is_hidden_from(username));
         if let Some(room) = room {
             let user_in_room = room.users.iter().find(|u| u.username == username);
             if let Some(_user_in_room) = user_in_room {
                 room.users.retain(|u| u.username != username);
+                room.read_markers.retain(|(u, _)| u != username);
                 // remove room from user's list of rooms
                 let mut users_guard = users.lock().await;
                 let user = users_guard.iter_mut().find(|u| u.username == username);
@@ -110,34 +267,39 @@ pub(crate) async fn handle_leave_room_command(
                     user.rooms.retain(|r| r != room_name);
                 }
                 drop(users_guard);
+                admit_from_waitlist(room, tx.clone(), users.clone()).await;
+                if room.is_abandoned() {
+                    let room_index = rooms_guard.iter().position(|r| r.name == room_name).unwrap();
+                    let room = rooms_guard.remove(room_index);
+                    println!("Room {} deleted, its last member left", room_name);
+                    close_room(room, "its last member left", tx.clone(), users.clone(), history, uploads).await;
+                }
+                drop(rooms_guard);
                 println!("User {} left room {}", username, room_name);
                 // write to user that they left the room
-                write_half
+                let _ = write_half
                     .write_all(format!("You left room {}\n", room_name).as_bytes())
-                    .await
-                    .unwrap();
+                    .await;
             } else {
-                write_half
+                let _ = write_half
                     .write_all(b"[i] You are not a member of this room\n")
-                    .await
-                    .unwrap();
+                    .await;
             }
         } else {
             println!("Room {} does not exist", room_name);
             // write to user that the room does not exist
-            write_half
+            let _ = write_half
                 .write_all(format!("Room {} does not exist\n", room_name).as_bytes())
-                .await
-                .unwrap();
+                .await;
         }
     } else {
-        write_half
+        let _ = write_half
             .write_all(format!("\n{}[i] No room name provided{}\n\n",color_codes::YELLOW, color_codes::RESET).as_bytes())
-            .await
-            .unwrap();
+            .await;
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn handle_m_room_command(
     write_half: &mut WriteHalf<'_>,
     line: &str,
@@ -145,174 +307,2612 @@ pub(crate) async fn handle_m_room_command(
     addr: std::net::SocketAddr,
     tx: broadcast::Sender<(String, std::net::SocketAddr)>,
     rooms: Arc<TokioMutex<Vec<Room>>>,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+    profiles: Arc<TokioMutex<Vec<Profile>>>,
+    history: Arc<TokioMutex<History>>,
 ) {
-    let mut parts = line.trim().split_whitespace();
+    let mut parts = line.split_whitespace();
     parts.next(); // skip /m_room
-    let room_name = parts.next().unwrap();
+    let Some(room_name) = parts.next() else {
+        let _ = write_half
+            .write_all(format!("\n{}[i] No room name provided{}\n\n", color_codes::YELLOW, color_codes::RESET).as_bytes())
+            .await;
+        return;
+    };
     let message = parts.collect::<Vec<&str>>().join(" ");
-    let rooms_guard = rooms.lock().await;
-    let room = rooms_guard.iter().find(|r| r.name == room_name);
+    send_room_message(write_half, room_name, &message, None, username, addr, tx, rooms, users, profiles, history).await;
+}
+
+// used by /m_room, /reply and for plain lines sent to the active room
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn send_room_message(
+    write_half: &mut WriteHalf<'_>,
+    room_name: &str,
+    message: &str,
+    reply_to: Option<&str>,
+    username: &str,
+    addr: std::net::SocketAddr,
+    tx: broadcast::Sender<(String, std::net::SocketAddr)>,
+    rooms: Arc<TokioMutex<Vec<Room>>>,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+    profiles: Arc<TokioMutex<Vec<Profile>>>,
+    history: Arc<TokioMutex<History>>,
+) {
+    let is_admin = is_admin(username, users.clone()).await;
+    let mut rooms_guard = rooms.lock().await;
+    let room = rooms_guard
+        .iter_mut()
+        .find(|r| r.name == room_name && !r.is_hidden_from(username));
     if let Some(room) = room {
         let user_in_room = room.users.iter().find(|u| u.username == username);
-        if let Some(_user_in_room) = user_in_room {
-            let msg_with_username = format!("[{}] [{}] {}\n", room_name, username, message);
-            tx.send((msg_with_username.clone(), addr)).unwrap();
-        } else {
-            write_half
+        if user_in_room.is_none() {
+            let _ = write_half
                 .write_all(b"[i] You are not a member of this room\n")
-                .await
-                .unwrap();
+                .await;
+        } else if room.read_only && !room.is_operator(username) && !is_admin {
+            let _ = write_half
+                .write_all(format!("[i] Room {} is read only\n", room_name).as_bytes())
+                .await;
+        } else if history::mention_names(message).iter().any(|name| *name == "room" || *name == "here")
+            && !room.is_operator(username)
+            && !is_admin
+        {
+            let _ = write_half
+                .write_all(b"[i] Only room operators can use @room and @here\n")
+                .await;
+        } else {
+            room.last_activity = Instant::now();
+            let mentions = mentioned_users(message, username, room, users.clone(), profiles).await;
+            let sender_name = display_name(username, users.clone()).await;
+            let mut history_guard = history.lock().await;
+            // replies to a reply join the thread of the message they reply to
+            let (thread, quote) = match reply_to.and_then(|parent_id| history_guard.find(parent_id)) {
+                Some(parent) => (
+                    Some(parent.thread.clone().unwrap_or_else(|| parent.id.clone())),
+                    format!("(reply to #{} [{}]: \"{}\") ", parent.id, parent.author_name, parent.snippet()),
+                ),
+                None => (None, String::new()),
+            };
+            let id = history_guard.record(Conversation::Room(room_name.to_string()), username, &sender_name, message, thread, mentions);
+            drop(history_guard);
+            let msg_with_username = format!("[{}] #{} [{}] {}{}\n", room_name, id, sender_name, quote, message);
+            tx.send((msg_with_username.clone(), addr)).unwrap();
+            drop(rooms_guard);
+            let _ = write_half
+                .write_all(format!("[i] Sent as #{}\n", id).as_bytes())
+                .await;
         }
     } else {
-        write_half
+        let _ = write_half
             .write_all(b"Room does not exist\n")
-            .await
-            .unwrap();
+            .await;
     }
 }
 
-pub(crate) async fn handle_view_users_command(
+// usernames mentioned in a message without the sender, @room and @here cover the room's members
+// and any other @username that has ever connected is kept so they see it in /mentions later
+async fn mentioned_users(
+    message: &str,
+    sender: &str,
+    room: &Room,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+    profiles: Arc<TokioMutex<Vec<Profile>>>,
+) -> Vec<String> {
+    let users_guard = users.lock().await;
+    let profiles_guard = profiles.lock().await;
+    let online = room.users.iter().map(|u| u.username.as_str());
+    let members: Vec<&str> = online.clone().chain(room.offline_members.iter().map(|u| u.as_str())).collect();
+    let mut mentioned: Vec<String> = vec![];
+    for name in history::mention_names(message) {
+        let names: Vec<&str> = match name {
+            "room" => members.clone(),
+            // members who are online and not away
+            "here" => online
+                .clone()
+                .filter(|member| users_guard.iter().any(|u| u.username == *member && u.away.is_none()))
+                .collect(),
+            name if profiles_guard.iter().any(|p| p.username == name) => vec![name],
+            _ => vec![],
+        };
+        for name in names {
+            if name != sender && !mentioned.iter().any(|m| m == name) {
+                mentioned.push(name.to_string());
+            }
+        }
+    }
+    mentioned
+}
+
+// replies go to the room of the message they reply to
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn handle_reply_command(
     write_half: &mut WriteHalf<'_>,
     line: &str,
     username: &str,
+    addr: std::net::SocketAddr,
+    tx: broadcast::Sender<(String, std::net::SocketAddr)>,
     rooms: Arc<TokioMutex<Vec<Room>>>,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+    profiles: Arc<TokioMutex<Vec<Profile>>>,
+    history: Arc<TokioMutex<History>>,
 ) {
-    let mut parts = line.trim().split_whitespace();
-    parts.next(); // skip /view_users
-    let room_name = parts.next().unwrap();
-    let rooms_guard = rooms.lock().await;
-    let room = rooms_guard.iter().find(|r| r.name == room_name);
-    if let Some(room) = room {
-        let user_in_room = room.users.iter().find(|u| u.username == username);
-        if let Some(_user_in_room) = user_in_room {
-            for user in room.users.iter() {
-                write_half
-                    .write_all(format!("[{}]\n", user.username).as_bytes())
-                    .await
-                    .unwrap();
-            }
-        } else {
-            write_half
-                .write_all(b"[i] Member lists are private. Join room to view.\n")
-                .await
-                .unwrap();
+    let mut parts = line.split_whitespace();
+    parts.next(); // skip /reply
+    let id = parts.next().map(history::parse_id);
+    let message = parts.collect::<Vec<&str>>().join(" ");
+    let Some(id) = id.filter(|_| !message.is_empty()) else {
+        let _ = write_half
+            .write_all(format!("\n{}[i] Usage: /reply <message_id> <text>{}\n\n", color_codes::YELLOW, color_codes::RESET).as_bytes())
+            .await;
+        return;
+    };
+
+    let history_guard = history.lock().await;
+    let room_name = match history_guard.find(id).map(|parent| &parent.conversation) {
+        Some(Conversation::Room(room_name)) => Ok(room_name.clone()),
+        Some(_) => Err("[i] You can only reply to room messages\n".to_string()),
+        None => Err(format!("[i] No message with id #{}\n", id)),
+    };
+    drop(history_guard);
+
+    match room_name {
+        Ok(room_name) => {
+            send_room_message(write_half, &room_name, &message, Some(id), username, addr, tx, rooms, users, profiles, history).await;
+        }
+        Err(reply) => {
+            let _ = write_half.write_all(reply.as_bytes()).await;
         }
-    } else {
-        write_half
-            .write_all(b"Room does not exist\n")
-            .await
-            .unwrap();
     }
 }
-pub(crate) async fn handle_list_command(
+
+pub(crate) async fn handle_thread_command(
     write_half: &mut WriteHalf<'_>,
-    users: Arc<TokioMutex<Vec<UserInfo>>>,
+    line: &str,
+    username: &str,
+    rooms: Arc<TokioMutex<Vec<Room>>>,
+    history: Arc<TokioMutex<History>>,
 ) {
-    let users_guard = users.lock().await;
-    for user in users_guard.iter() {
-        write_half
-            .write_all(format!("[{}]\n", user.username).as_bytes())
-            .await
-            .unwrap();
+    let mut parts = line.split_whitespace();
+    parts.next(); // skip /thread
+    let Some(id) = parts.next().map(history::parse_id) else {
+        let _ = write_half
+            .write_all(format!("\n{}[i] Usage: /thread <message_id>{}\n\n", color_codes::YELLOW, color_codes::RESET).as_bytes())
+            .await;
+        return;
+    };
+
+    let rooms_guard = rooms.lock().await;
+    let history_guard = history.lock().await;
+    let reply = match history_guard.find(id) {
+        Some(message) => match &message.conversation {
+            Conversation::Room(room_name)
+                if rooms_guard
+                    .iter()
+                    .any(|r| &r.name == room_name && !r.is_hidden_from(username) && r.is_member(username)) =>
+            {
+                let root_id = message.thread.as_deref().unwrap_or(&message.id);
+                let mut reply = format!("[i] Thread #{} in {}:\n", root_id, room_name);
+                for message in history_guard.thread_messages(root_id) {
+                    // replies are indented under the message that started the thread
+                    let indent = if message.thread.is_some() { "  " } else { "" };
+                    reply.push_str(&format!("{}{} {}\n", indent, format_timestamp(message.sent_at), message.render()));
+                }
+                reply
+            }
+            Conversation::Room(_) => "[i] You are not a member of this room\n".to_string(),
+            _ => "[i] Private messages have no threads\n".to_string(),
+        },
+        None => format!("[i] No message with id #{}\n", id),
+    };
+    drop(history_guard);
+    drop(rooms_guard);
+
+    let _ = write_half.write_all(reply.as_bytes()).await;
+}
+
+// returns the room to send plain lines to from now on, if the user may switch to it
+pub(crate) async fn handle_switch_command(
+    write_half: &mut WriteHalf<'_>,
+    line: &str,
+    username: &str,
+    active_room: &str,
+    rooms: Arc<TokioMutex<Vec<Room>>>,
+) -> Option<String> {
+    let mut parts = line.split_whitespace();
+    parts.next(); // skip /switch
+    let Some(room_name) = parts.next() else {
+        let _ = write_half
+            .write_all(format!("[i] Plain messages go to {}\n", active_room).as_bytes())
+            .await;
+        return None;
+    };
+
+    let rooms_guard = rooms.lock().await;
+    let room = rooms_guard
+        .iter()
+        .find(|r| r.name == room_name && !r.is_hidden_from(username));
+    let (reply, switched) = match room {
+        Some(room) if room.is_member(username) => (format!("[i] Plain messages now go to {}\n", room_name), true),
+        Some(_) => ("[i] You are not a member of this room\n".to_string(), false),
+        None => (format!("Room {} does not exist\n", room_name), false),
+    };
+    drop(rooms_guard);
+
+    let _ = write_half.write_all(reply.as_bytes()).await;
+    switched.then(|| room_name.to_string())
+}
+
+// every user starts out in the global room unless it is full, returns the banner if there is a topic
+pub(crate) async fn join_global_room(
+    username: &str,
+    addr: std::net::SocketAddr,
+    rooms: Arc<TokioMutex<Vec<Room>>>,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+) -> Option<String> {
+    let mut rooms_guard = rooms.lock().await;
+    let room = rooms_guard.iter_mut().find(|r| r.name == config::GLOBAL_ROOM)?;
+    if room.is_member(username) || room.is_full() {
+        return None;
     }
+    let banner = add_member(room, username, addr, users).await;
+    // only show the banner when there is a topic, e.g. an announcement
+    room.topic.is_some().then_some(banner)
 }
 
-pub(crate) async fn handle_report_command(
+pub(crate) async fn handle_read_only_command(
     write_half: &mut WriteHalf<'_>,
-    reported_user: &str,
+    line: &str,
     username: &str,
+    addr: std::net::SocketAddr,
+    tx: broadcast::Sender<(String, std::net::SocketAddr)>,
+    rooms: Arc<TokioMutex<Vec<Room>>>,
     users: Arc<TokioMutex<Vec<UserInfo>>>,
 ) {
-    let users_guard = users.lock().await;
-    let reported_user_info = users_guard.iter().find(|u| u.username == reported_user);
-    if let Some(_reported_user_info) = reported_user_info {
-        println!("User {} reported {}", username, reported_user);
-    } else {
-        write_half
-            .write_all(format!("User {} does not exist\n", reported_user).as_bytes())
-            .await
-            .unwrap();
-    }
+    let mut parts = line.split_whitespace();
+    parts.next(); // skip /read_only
+    let (Some(room_name), Some(mode)) = (parts.next(), parts.next()) else {
+        let _ = write_half
+            .write_all(format!("\n{}[i] Usage: /read_only <room_name> <on|off>{}\n\n", color_codes::YELLOW, color_codes::RESET).as_bytes())
+            .await;
+        return;
+    };
+    let read_only = match mode {
+        "on" => true,
+        "off" => false,
+        _ => {
+            let _ = write_half
+                .write_all(format!("\n{}[i] Read only mode must be 'on' or 'off'{}\n\n", color_codes::RED, color_codes::RESET).as_bytes())
+                .await;
+            return;
+        }
+    };
+
+    let is_admin = is_admin(username, users.clone()).await;
+    let mut rooms_guard = rooms.lock().await;
+    let room = rooms_guard
+        .iter_mut()
+        .find(|r| r.name == room_name && !r.is_hidden_from(username));
+    let reply = match room {
+        Some(room) if room.is_operator(username) || is_admin => {
+            room.read_only = read_only;
+            let announcement = if read_only {
+                format!("[{}] [i] The room is now read only, only operators can post\n", room_name)
+            } else {
+                format!("[{}] [i] Everyone can post in the room again\n", room_name)
+            };
+            tx.send((announcement, addr)).unwrap();
+            println!("User {} turned read only mode {} for room {}", username, mode, room_name);
+            format!("[i] Read only mode is {} for {}\n", mode, room_name)
+        }
+        Some(_) => "[i] Only room operators can change read only mode\n".to_string(),
+        None => format!("Room {} does not exist\n", room_name),
+    };
+    drop(rooms_guard);
+
+    let _ = write_half.write_all(reply.as_bytes()).await;
 }
 
-pub(crate) async fn handle_pm_command(
+pub(crate) async fn handle_topic_command(
     write_half: &mut WriteHalf<'_>,
-    recipient: &str,
-    message: &str,
-    sender: &str,
+    line: &str,
+    username: &str,
+    addr: std::net::SocketAddr,
     tx: broadcast::Sender<(String, std::net::SocketAddr)>,
+    rooms: Arc<TokioMutex<Vec<Room>>>,
     users: Arc<TokioMutex<Vec<UserInfo>>>,
 ) {
-    let users_guard = users.lock().await;
-    let recipient_info = users_guard.iter().find(|u| u.username == recipient);
-    if let Some(recipient_info) = recipient_info {
-        let msg = format!("[PM] [{}] {}\n", sender, message);
-        tx.send((msg.clone(), recipient_info.addr)).unwrap();
-    } else {
-        write_half.write_all(b"User not found\n").await.unwrap();
-    }
+    let mut parts = line.split_whitespace();
+    parts.next(); // skip /topic
+    let Some(room_name) = parts.next() else {
+        let _ = write_half
+            .write_all(format!("\n{}[i] No room name provided{}\n\n", color_codes::YELLOW, color_codes::RESET).as_bytes())
+            .await;
+        return;
+    };
+    let topic = parts.collect::<Vec<&str>>().join(" ");
+
+    let is_admin = is_admin(username, users.clone()).await;
+    let mut rooms_guard = rooms.lock().await;
+    let room = rooms_guard
+        .iter_mut()
+        .find(|r| r.name == room_name && !r.is_hidden_from(username));
+    let reply = match room {
+        Some(room) if room.is_operator(username) || is_admin => {
+            let announcement = if topic.is_empty() {
+                room.topic = None;
+                format!("[{}] [i] {} cleared the topic\n", room_name, username)
+            } else {
+                room.topic = Some(topic.clone());
+                format!("[{}] [i] {} changed the topic to: {}\n", room_name, username, topic)
+            };
+            tx.send((announcement, addr)).unwrap();
+            println!("User {} changed the topic of room {}", username, room_name);
+            "[i] Topic updated\n".to_string()
+        }
+        Some(_) => "[i] Only room operators can change the topic\n".to_string(),
+        None => format!("Room {} does not exist\n", room_name),
+    };
+    drop(rooms_guard);
+
+    let _ = write_half.write_all(reply.as_bytes()).await;
 }
 
-pub(crate) async fn handle_help_command(write_half: &mut WriteHalf<'_>, line: &str) {
-    let mut parts = line.trim().split_whitespace();
-    parts.next(); // skip /help
+pub(crate) async fn handle_describe_command(
+    write_half: &mut WriteHalf<'_>,
+    line: &str,
+    username: &str,
+    rooms: Arc<TokioMutex<Vec<Room>>>,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+) {
+    let mut parts = line.split_whitespace();
+    parts.next(); // skip /describe
+    let Some(room_name) = parts.next() else {
+        let _ = write_half
+            .write_all(format!("\n{}[i] No room name provided{}\n\n", color_codes::YELLOW, color_codes::RESET).as_bytes())
+            .await;
+        return;
+    };
+    let description = parts.collect::<Vec<&str>>().join(" ");
 
-    while let Some(command) = parts.next() {
-        match command {
-            "/create_room" => {
-                write_half.write_all(format!("{}\n/create_room <room_name> - Create a new chat room.\nUse an underscore between multi-word room names.\nRoom names 'glb' and 'adm' are reserved.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
-                    .await
-                    .unwrap();
-            }
-            "/join_room" => {
-                write_half.write_all(format!("{}\n/join_room <room_name> - Join an existing chat room.\nYou must provide a valid room name.\nUse '/view_rooms' to list available rooms.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
-                    .await
-                    .unwrap();
-            }
-            "/view_rooms" => {
-                write_half.write_all(format!("{}\n/view_rooms - View all chat rooms.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
-                    .await
-                    .unwrap();
-            }
-            "/leave_room" => {
-                write_half.write_all(format!("{}\n/leave_room <room_name> - Leave a chat room.\nYou must be a member of the room to leave it.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
-                    .await
-                    .unwrap();
+    let is_admin = is_admin(username, users.clone()).await;
+    let mut rooms_guard = rooms.lock().await;
+    let room = rooms_guard
+        .iter_mut()
+        .find(|r| r.name == room_name && !r.is_hidden_from(username));
+    let reply = match room {
+        Some(room) if room.is_operator(username) || is_admin => {
+            room.description = if description.is_empty() { None } else { Some(description) };
+            println!("User {} changed the description of room {}", username, room_name);
+            "[i] Description updated\n".to_string()
+        }
+        Some(_) => "[i] Only room operators can change the description\n".to_string(),
+        None => format!("Room {} does not exist\n", room_name),
+    };
+    drop(rooms_guard);
+
+    let _ = write_half.write_all(reply.as_bytes()).await;
+}
+
+pub(crate) async fn handle_visibility_command(
+    write_half: &mut WriteHalf<'_>,
+    line: &str,
+    username: &str,
+    rooms: Arc<TokioMutex<Vec<Room>>>,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+) {
+    let mut parts = line.split_whitespace();
+    parts.next(); // skip /visibility
+    let (Some(room_name), Some(mode)) = (parts.next(), parts.next()) else {
+        let _ = write_half
+            .write_all(format!("\n{}[i] Usage: /visibility <room_name> <public|unlisted|invite_only>{}\n\n", color_codes::YELLOW, color_codes::RESET).as_bytes())
+            .await;
+        return;
+    };
+    let Some(visibility) = Visibility::parse(mode) else {
+        let _ = write_half
+            .write_all(format!("\n{}[i] Room visibility must be public, unlisted or invite_only{}\n\n", color_codes::RED, color_codes::RESET).as_bytes())
+            .await;
+        return;
+    };
+
+    let is_admin = is_admin(username, users.clone()).await;
+    let mut rooms_guard = rooms.lock().await;
+    let room = rooms_guard
+        .iter_mut()
+        .find(|r| r.name == room_name && !r.is_hidden_from(username));
+    let reply = match room {
+        Some(room) if room.is_operator(username) || is_admin => {
+            room.visibility = visibility;
+            println!("User {} made room {} {}", username, room_name, visibility.label());
+            format!("[i] Room {} is now {}\n", room_name, visibility.label())
+        }
+        Some(_) => "[i] Only room operators can change the visibility\n".to_string(),
+        None => format!("Room {} does not exist\n", room_name),
+    };
+    drop(rooms_guard);
+
+    let _ = write_half.write_all(reply.as_bytes()).await;
+}
+
+pub(crate) async fn handle_room_info_command(
+    write_half: &mut WriteHalf<'_>,
+    line: &str,
+    username: &str,
+    rooms: Arc<TokioMutex<Vec<Room>>>,
+) {
+    let mut parts = line.split_whitespace();
+    parts.next(); // skip /room_info
+    let Some(room_name) = parts.next() else {
+        let _ = write_half
+            .write_all(format!("\n{}[i] No room name provided{}\n\n", color_codes::YELLOW, color_codes::RESET).as_bytes())
+            .await;
+        return;
+    };
+
+    let rooms_guard = rooms.lock().await;
+    let room = rooms_guard
+        .iter()
+        .find(|r| r.name == room_name && !r.is_hidden_from(username));
+    let reply = match room {
+        Some(room) => {
+            let mut info = format!("\n{}[i] {}{}\n", color_codes::GREEN, room.name, color_codes::RESET);
+            info.push_str(&room.banner());
+            info.push_str(&format!("Visibility: {}\n", room.visibility.label()));
+            info.push_str(&format!("Lifecycle: {}\n", room.lifecycle.describe()));
+            if let Lifecycle::Ttl(ttl) = room.lifecycle {
+                let remaining = ttl.saturating_sub(room.last_activity.elapsed());
+                info.push_str(&format!("Expires in: {} unless there is activity\n", format_duration(remaining)));
             }
-            "/m_room" => {
-                write_half.write_all(format!("{}\n/m_room <room_name> <message> - Send a message to all users in a specific room.\nYou must be a member of the room to send a message.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
-                    .await
-                    .unwrap();
+            match room.max_members {
+                Some(max) => info.push_str(&format!("Members: {}/{}\n", room.users.len(), max)),
+                None => info.push_str(&format!("Members: {}\n", room.users.len())),
             }
-            "/view_users" => {
-                write_half.write_all(format!("{}\n/view_users <room_name> - View users in a specific chat room.\nYou must be a member of the room to view its users.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
-                    .await
-                    .unwrap();
+            if !room.waitlist.is_empty() {
+                info.push_str(&format!("Waitlist: {}\n", room.waitlist.len()));
             }
-            "/list" => {
-                write_half.write_all(format!("{}\n/list - List all connected users.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
-                    .await
-                    .unwrap();
+            info.push_str(&format!("Operators: {}\n\n", room.operators.join(", ")));
+            info
+        }
+        None => format!("Room {} does not exist\n", room_name),
+    };
+    drop(rooms_guard);
+
+    let _ = write_half.write_all(reply.as_bytes()).await;
+}
+
+pub(crate) async fn handle_capacity_command(
+    write_half: &mut WriteHalf<'_>,
+    line: &str,
+    username: &str,
+    tx: broadcast::Sender<(String, std::net::SocketAddr)>,
+    rooms: Arc<TokioMutex<Vec<Room>>>,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+) {
+    let mut parts = line.split_whitespace();
+    parts.next(); // skip /capacity
+    let (Some(room_name), Some(limit)) = (parts.next(), parts.next()) else {
+        let _ = write_half
+            .write_all(format!("\n{}[i] Usage: /capacity <room_name> <max_members|none>{}\n\n", color_codes::YELLOW, color_codes::RESET).as_bytes())
+            .await;
+        return;
+    };
+    let max_members = match limit {
+        "none" => None,
+        limit => match limit.parse::<usize>() {
+            Ok(max) if max > 0 => Some(max),
+            _ => {
+                let _ = write_half
+                    .write_all(format!("\n{}[i] Capacity must be a positive number or 'none'{}\n\n", color_codes::RED, color_codes::RESET).as_bytes())
+                    .await;
+                return;
             }
-            "/report" => {
-                write_half.write_all(format!("{}\n/report <username> - Report a user to the server admin.\nYou must provide a valid username.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
-                    .await
-                    .unwrap();
+        },
+    };
+
+    let is_admin = is_admin(username, users.clone()).await;
+    let mut rooms_guard = rooms.lock().await;
+    let room = rooms_guard
+        .iter_mut()
+        .find(|r| r.name == room_name && !r.is_hidden_from(username));
+    let reply = match room {
+        Some(room) if room.is_operator(username) || is_admin => {
+            room.max_members = max_members;
+            // raising the limit may free spots for waiting users
+            admit_from_waitlist(room, tx.clone(), users.clone()).await;
+            println!("User {} set the capacity of room {} to {}", username, room_name, limit);
+            match max_members {
+                Some(max) => format!("[i] Room {} now allows {} members\n", room_name, max),
+                None => format!("[i] Room {} no longer has a member limit\n", room_name),
             }
-            "/pm" => {
-                write_half.write_all(format!("{}\n/pm <username> <message> - Send a private message to any connected user.\nYou must provide a valid username and a message.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
-                    .await
-                    .unwrap();
+        }
+        Some(_) => "[i] Only room operators can change the capacity\n".to_string(),
+        None => format!("Room {} does not exist\n", room_name),
+    };
+    drop(rooms_guard);
+
+    let _ = write_half.write_all(reply.as_bytes()).await;
+}
+
+pub(crate) async fn handle_waitlist_command(
+    write_half: &mut WriteHalf<'_>,
+    line: &str,
+    username: &str,
+    rooms: Arc<TokioMutex<Vec<Room>>>,
+) {
+    let mut parts = line.split_whitespace();
+    parts.next(); // skip /waitlist
+    let Some(room_name) = parts.next() else {
+        let _ = write_half
+            .write_all(format!("\n{}[i] No room name provided{}\n\n", color_codes::YELLOW, color_codes::RESET).as_bytes())
+            .await;
+        return;
+    };
+    let cancel = parts.next() == Some("cancel");
+
+    let mut rooms_guard = rooms.lock().await;
+    let room = rooms_guard
+        .iter_mut()
+        .find(|r| r.name == room_name && (r.is_member(username) || r.is_joinable_by(username)));
+    let reply = match room {
+        Some(room) if cancel => {
+            if room.waitlist.iter().any(|u| u == username) {
+                room.waitlist.retain(|u| u != username);
+                format!("[i] You left the waitlist for {}\n", room_name)
+            } else {
+                format!("[i] You are not on the waitlist for {}\n", room_name)
             }
-            "/exit" => {
-                write_half.write_all(format!("{}\n/exit - Disconnect from the server{}\n\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
-                    .await
-                    .unwrap();
+        }
+        Some(room) if room.is_member(username) => "[i] You are already a member of this room\n".to_string(),
+        Some(room) if !room.is_full() => format!("[i] Room {} has free spots, use /join_room {}\n", room_name, room_name),
+        Some(room) => {
+            if !room.waitlist.iter().any(|u| u == username) {
+                room.waitlist.push(username.to_string());
+                println!("User {} joined the waitlist for room {}", username, room_name);
             }
-            _ => {
-                write_half.write_all(format!("{}\nNo such command{}\n\n", color_codes::RED, color_codes::RESET).as_bytes()).await.unwrap();
-                break;
+            let position = room.waitlist.iter().position(|u| u == username).unwrap() + 1;
+            format!("[i] You are number {} on the waitlist for {}\n", position, room_name)
+        }
+        None => format!("Room {} does not exist\n", room_name),
+    };
+    drop(rooms_guard);
+
+    let _ = write_half.write_all(reply.as_bytes()).await;
+}
+
+pub(crate) async fn handle_invite_command(
+    write_half: &mut WriteHalf<'_>,
+    line: &str,
+    username: &str,
+    tx: broadcast::Sender<(String, std::net::SocketAddr)>,
+    rooms: Arc<TokioMutex<Vec<Room>>>,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+) {
+    let mut parts = line.split_whitespace();
+    parts.next(); // skip /invite
+    let (Some(invitee), Some(room_name)) = (parts.next(), parts.next()) else {
+        let _ = write_half
+            .write_all(format!("\n{}[i] Usage: /invite <username> <room_name>{}\n\n", color_codes::YELLOW, color_codes::RESET).as_bytes())
+            .await;
+        return;
+    };
+
+    let mut rooms_guard = rooms.lock().await;
+    let room = rooms_guard
+        .iter_mut()
+        .find(|r| r.name == room_name && !r.is_hidden_from(username));
+    let reply = match room {
+        Some(room) if !room.is_member(username) && !room.is_operator(username) => {
+            "[i] You are not a member of this room\n".to_string()
+        }
+        Some(room) if room.is_member(invitee) => format!("[i] {} is already a member of {}\n", invitee, room_name),
+        Some(room) if room.invited.iter().any(|u| u == invitee) => {
+            format!("[i] {} has already been invited to {}\n", invitee, room_name)
+        }
+        Some(room) => {
+            let users_guard = users.lock().await;
+            let invitee_addr = users_guard.iter().find(|u| u.username == invitee).map(|u| u.addr);
+            drop(users_guard);
+            match invitee_addr {
+                Some(invitee_addr) => {
+                    room.invited.push(invitee.to_string());
+                    let notice = format!(
+                        "[notice] {} invited you to room {}. Use /accept {} or /decline {}\n",
+                        username, room_name, room_name, room_name
+                    );
+                    tx.send((notice, invitee_addr)).unwrap();
+                    println!("User {} invited {} to room {}", username, invitee, room_name);
+                    format!("[i] Invited {} to {}\n", invitee, room_name)
+                }
+                None => "User not found\n".to_string(),
             }
         }
+        None => format!("Room {} does not exist\n", room_name),
+    };
+    drop(rooms_guard);
+
+    let _ = write_half.write_all(reply.as_bytes()).await;
+}
+
+pub(crate) async fn handle_invites_command(
+    write_half: &mut WriteHalf<'_>,
+    username: &str,
+    rooms: Arc<TokioMutex<Vec<Room>>>,
+) {
+    let rooms_guard = rooms.lock().await;
+    let invites: Vec<String> = rooms_guard
+        .iter()
+        .filter(|r| r.invited.iter().any(|u| u == username))
+        .map(|r| format!("[{}]\n", r.name))
+        .collect();
+    drop(rooms_guard);
+
+    if invites.is_empty() {
+        let _ = write_half.write_all(b"[i] You have no pending invites\n").await;
+        return;
     }
-}
\ No newline at end of file
+    for invite in invites {
+        let _ = write_half.write_all(invite.as_bytes()).await;
+    }
+}
+
+pub(crate) async fn handle_accept_command(
+    write_half: &mut WriteHalf<'_>,
+    line: &str,
+    username: &str,
+    addr: std::net::SocketAddr,
+    rooms: Arc<TokioMutex<Vec<Room>>>,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+    history: Arc<TokioMutex<History>>,
+) {
+    let mut parts = line.split_whitespace();
+    parts.next(); // skip /accept
+    let Some(room_name) = parts.next() else {
+        let _ = write_half
+            .write_all(format!("\n{}[i] No room name provided{}\n\n", color_codes::YELLOW, color_codes::RESET).as_bytes())
+            .await;
+        return;
+    };
+
+    let mut rooms_guard = rooms.lock().await;
+    let room = rooms_guard
+        .iter_mut()
+        .find(|r| r.name == room_name && r.invited.iter().any(|u| u == username));
+    let reply = match room {
+        // the invite stays pending so it can still be used once there is a spot
+        Some(room) if room.is_full() => room_full_message(room),
+        Some(room) => {
+            let banner = add_member(room, username, addr, users.clone()).await;
+            let pins = pinned_messages(room, history).await;
+            println!("User {} accepted an invite to room {}", username, room_name);
+            format!("You joined room {}\n{}{}", room_name, banner, pins)
+        }
+        None => format!("[i] You have no invite to room {}\n", room_name),
+    };
+    drop(rooms_guard);
+
+    let _ = write_half.write_all(reply.as_bytes()).await;
+}
+
+pub(crate) async fn handle_decline_command(
+    write_half: &mut WriteHalf<'_>,
+    line: &str,
+    username: &str,
+    rooms: Arc<TokioMutex<Vec<Room>>>,
+) {
+    let mut parts = line.split_whitespace();
+    parts.next(); // skip /decline
+    let Some(room_name) = parts.next() else {
+        let _ = write_half
+            .write_all(format!("\n{}[i] No room name provided{}\n\n", color_codes::YELLOW, color_codes::RESET).as_bytes())
+            .await;
+        return;
+    };
+
+    let mut rooms_guard = rooms.lock().await;
+    let room = rooms_guard
+        .iter_mut()
+        .find(|r| r.name == room_name && r.invited.iter().any(|u| u == username));
+    let reply = match room {
+        Some(room) => {
+            room.invited.retain(|u| u != username);
+            println!("User {} declined an invite to room {}", username, room_name);
+            format!("[i] Declined the invite to {}\n", room_name)
+        }
+        None => format!("[i] You have no invite to room {}\n", room_name),
+    };
+    drop(rooms_guard);
+
+    let _ = write_half.write_all(reply.as_bytes()).await;
+}
+
+// admins can act as operators of every room
+async fn is_admin(username: &str, users: Arc<TokioMutex<Vec<UserInfo>>>) -> bool {
+    let users_guard = users.lock().await;
+    users_guard
+        .iter()
+        .any(|u| u.username == username && u.role == Role::Admin)
+}
+
+pub(crate) async fn handle_view_rooms_command(
+    write_half: &mut WriteHalf<'_>,
+    line: &str,
+    username: &str,
+    rooms: Arc<TokioMutex<Vec<Room>>>,
+) {
+    let mut parts = line.split_whitespace();
+    parts.next(); // skip /view_rooms
+    let mut pattern = None;
+    let mut page = 1;
+    for part in parts {
+        match part.strip_prefix("page:") {
+            Some(number) => page = number.parse().unwrap_or(1).max(1),
+            None => pattern = Some(part),
+        }
+    }
+
+    let rooms_guard = rooms.lock().await;
+    let mut matching: Vec<&Room> = rooms_guard
+        .iter()
+        .filter(|r| !r.is_hidden_from(username))
+        .filter(|r| pattern.is_none_or(|p| matches_pattern(&r.name, p)))
+        .collect();
+    // most recently active rooms first
+    matching.sort_by_key(|room| std::cmp::Reverse(room.last_activity));
+
+    let total = matching.len();
+    let pages = total.div_ceil(config::ROOMS_PER_PAGE).max(1);
+    let room_lines: Vec<String> = matching
+        .iter()
+        .skip((page - 1) * config::ROOMS_PER_PAGE)
+        .take(config::ROOMS_PER_PAGE)
+        .map(|room| {
+            let members = room.users.len();
+            let mut room_line = match room.max_members {
+                Some(max) => format!("[{}] {}/{} members", room.name, members, max),
+                None => format!(
+                    "[{}] {} member{}",
+                    room.name,
+                    members,
+                    if members == 1 { "" } else { "s" }
+                ),
+            };
+            if room.visibility != Visibility::Public {
+                room_line.push_str(&format!(" ({})", room.visibility.label()));
+            }
+            if room.is_member(username) {
+                room_line.push_str(" (joined)");
+            }
+            if let Some(topic) = &room.topic {
+                room_line.push_str(&format!(" - {}", topic));
+            }
+            room_line.push('\n');
+            room_line
+        })
+        .collect();
+    drop(rooms_guard);
+
+    if room_lines.is_empty() {
+        let _ = write_half.write_all(b"[i] No rooms found\n").await;
+        return;
+    }
+    for room_line in room_lines {
+        let _ = write_half.write_all(room_line.as_bytes()).await;
+    }
+    if pages > 1 {
+        let _ = write_half
+            .write_all(format!("[i] Page {}/{} of {} rooms, use page:<n> to see more\n", page, pages, total).as_bytes())
+            .await;
+    }
+}
+
+// '*' matches any run of characters, a pattern without '*' matches anywhere in the name
+fn matches_pattern(name: &str, pattern: &str) -> bool {
+    if !pattern.contains('*') {
+        return name.contains(pattern);
+    }
+
+    let pieces: Vec<&str> = pattern.split('*').collect();
+    let mut rest = name;
+    for (i, piece) in pieces.iter().enumerate() {
+        if i == 0 {
+            match rest.strip_prefix(piece) {
+                Some(remaining) => rest = remaining,
+                None => return false,
+            }
+        } else if i == pieces.len() - 1 {
+            return rest.ends_with(piece);
+        } else {
+            match rest.find(piece) {
+                Some(index) => rest = &rest[index + piece.len()..],
+                None => return false,
+            }
+        }
+    }
+    true
+}
+
+pub(crate) async fn handle_view_users_command(
+    write_half: &mut WriteHalf<'_>,
+    line: &str,
+    username: &str,
+    rooms: Arc<TokioMutex<Vec<Room>>>,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+) {
+    let mut parts = line.split_whitespace();
+    parts.next(); // skip /view_users
+    let Some(room_name) = parts.next() else {
+        let _ = write_half
+            .write_all(format!("\n{}[i] No room name provided{}\n\n", color_codes::YELLOW, color_codes::RESET).as_bytes())
+            .await;
+        return;
+    };
+    let rooms_guard = rooms.lock().await;
+    let room = rooms_guard
+        .iter()
+        .find(|r| r.name == room_name && !r.is_hidden_from(username));
+    if let Some(room) = room {
+        let user_in_room = room.users.iter().find(|u| u.username == username);
+        if let Some(_user_in_room) = user_in_room {
+            // room members are copies, presence lives in the main user list
+            let users_guard = users.lock().await;
+            let member_lines: Vec<String> = room
+                .users
+                .iter()
+                .map(|member| match users_guard.iter().find(|u| u.username == member.username) {
+                    Some(user) => format!("{} {}\n", user.listing_name(), user.presence()),
+                    None => format!("[{}]\n", member.username),
+                })
+                .collect();
+            drop(users_guard);
+            for member_line in member_lines {
+                let _ = write_half.write_all(member_line.as_bytes()).await;
+            }
+        } else {
+            let _ = write_half
+                .write_all(b"[i] Member lists are private. Join room to view.\n")
+                .await;
+        }
+    } else {
+        let _ = write_half
+            .write_all(b"Room does not exist\n")
+            .await;
+    }
+}
+pub(crate) async fn handle_list_command(
+    write_half: &mut WriteHalf<'_>,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+) {
+    let users_guard = users.lock().await;
+    for user in users_guard.iter() {
+        let _ = write_half
+            .write_all(format!("{} {}\n", user.listing_name(), user.presence()).as_bytes())
+            .await;
+    }
+}
+
+pub(crate) async fn handle_report_command(
+    write_half: &mut WriteHalf<'_>,
+    reported_user: &str,
+    username: &str,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+) {
+    let users_guard = users.lock().await;
+    let reported_user_info = users_guard.iter().find(|u| u.username == reported_user);
+    if let Some(_reported_user_info) = reported_user_info {
+        println!("User {} reported {}", username, reported_user);
+    } else {
+        let _ = write_half
+            .write_all(format!("User {} does not exist\n", reported_user).as_bytes())
+            .await;
+    }
+}
+
+pub(crate) async fn handle_pm_command(
+    write_half: &mut WriteHalf<'_>,
+    recipient: &str,
+    message: &str,
+    sender: &str,
+    tx: broadcast::Sender<(String, std::net::SocketAddr)>,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+    history: Arc<TokioMutex<History>>,
+) {
+    let users_guard = users.lock().await;
+    let recipient_info = users_guard.iter().find(|u| u.username == recipient);
+    if let Some(recipient_info) = recipient_info {
+        let id = history.lock().await.record(
+            Conversation::Private(sender.to_string(), recipient.to_string()),
+            sender,
+            sender,
+            message,
+            None,
+            vec![],
+        );
+        let msg = format!("[PM] #{} [{}] {}\n", id, sender, message);
+        tx.send((msg.clone(), recipient_info.addr)).unwrap();
+        let auto_reply = recipient_info
+            .away
+            .as_ref()
+            .map(|away| format!("[i] {} is away: {}\n", recipient, away.message));
+        drop(users_guard);
+        let _ = write_half
+            .write_all(format!("[i] Sent as #{}\n", id).as_bytes())
+            .await;
+        if let Some(auto_reply) = auto_reply {
+            let _ = write_half.write_all(auto_reply.as_bytes()).await;
+        }
+    } else {
+        let _ = write_half.write_all(b"User not found\n").await;
+    }
+}
+
+// the group is picked by id, or by its members for a comma separated list of usernames
+pub(crate) async fn handle_gm_command(
+    write_half: &mut WriteHalf<'_>,
+    line: &str,
+    username: &str,
+    tx: broadcast::Sender<(String, std::net::SocketAddr)>,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+    history: Arc<TokioMutex<History>>,
+) {
+    let mut parts = line.split_whitespace();
+    parts.next(); // skip /gm
+    let target = parts.next();
+    let message = parts.collect::<Vec<&str>>().join(" ");
+    let Some(target) = target.filter(|_| !message.is_empty()) else {
+        let _ = write_half
+            .write_all(format!("\n{}[i] Usage: /gm <username,username,...|group_id> <message>{}\n\n", color_codes::YELLOW, color_codes::RESET).as_bytes())
+            .await;
+        return;
+    };
+
+    let users_guard = users.lock().await;
+    let mut history_guard = history.lock().await;
+    let group = match history_guard.group(target) {
+        Some(group) if group.members.iter().any(|m| m == username) => Ok((group.id.clone(), false)),
+        Some(_) => Err(format!("[i] You are not in group {}\n", target)),
+        None => {
+            let mut members = vec![username.to_string()];
+            for name in target.split(',').filter(|name| !name.is_empty()) {
+                if !members.iter().any(|m| m == name) {
+                    members.push(name.to_string());
+                }
+            }
+            let missing = members.iter().find(|m| !users_guard.iter().any(|u| &u.username == *m));
+            if members.len() < 2 {
+                Err("[i] Name at least one other user\n".to_string())
+            } else if members.len() > config::MAX_GROUP_MEMBERS {
+                Err(format!("[i] Groups can have at most {} members\n", config::MAX_GROUP_MEMBERS))
+            } else if let Some(missing) = missing {
+                Err(format!("User {} not found\n", missing))
+            } else {
+                Ok(history_guard.find_or_create_group(&members))
+            }
+        }
+    };
+
+    let reply = match group {
+        Ok((group_id, created)) => {
+            let members = history_guard.participants(&Conversation::Group(group_id.clone()));
+            let sender_name = users_guard
+                .iter()
+                .find(|u| u.username == username)
+                .map_or(username, |u| u.display_name())
+                .to_string();
+            let id = history_guard.record(Conversation::Group(group_id.clone()), username, &sender_name, &message, None, vec![]);
+            for user in users_guard
+                .iter()
+                .filter(|u| u.username != username && members.contains(&u.username))
+            {
+                if created {
+                    let notice = format!("[GM {}] [i] {} started a group with {}\n", group_id, sender_name, members.join(", "));
+                    tx.send((notice, user.addr)).unwrap();
+                }
+                tx.send((format!("[GM {}] #{} [{}] {}\n", group_id, id, sender_name, message), user.addr)).unwrap();
+            }
+            if created {
+                println!("User {} started group {} with {}", username, group_id, members.join(", "));
+                format!("[i] Started group {} with {}, use /gm {} to write to it\n[i] Sent as #{}\n", group_id, members.join(", "), group_id, id)
+            } else {
+                format!("[i] Sent as #{}\n", id)
+            }
+        }
+        Err(reply) => reply,
+    };
+    drop(history_guard);
+    drop(users_guard);
+
+    let _ = write_half.write_all(reply.as_bytes()).await;
+}
+
+pub(crate) async fn handle_gm_add_command(
+    write_half: &mut WriteHalf<'_>,
+    line: &str,
+    username: &str,
+    tx: broadcast::Sender<(String, std::net::SocketAddr)>,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+    history: Arc<TokioMutex<History>>,
+) {
+    let mut parts = line.split_whitespace();
+    parts.next(); // skip /gm_add
+    let (Some(group_id), Some(new_member)) = (parts.next(), parts.next()) else {
+        let _ = write_half
+            .write_all(format!("\n{}[i] Usage: /gm_add <group_id> <username>{}\n\n", color_codes::YELLOW, color_codes::RESET).as_bytes())
+            .await;
+        return;
+    };
+
+    let users_guard = users.lock().await;
+    let mut history_guard = history.lock().await;
+    let reply = match history_guard.group_mut(group_id) {
+        Some(group) if !group.members.iter().any(|m| m == username) => format!("[i] You are not in group {}\n", group_id),
+        Some(group) if group.members.iter().any(|m| m == new_member) => format!("[i] {} is already in group {}\n", new_member, group_id),
+        Some(_) if !users_guard.iter().any(|u| u.username == new_member) => format!("User {} not found\n", new_member),
+        Some(group) if group.members.len() >= config::MAX_GROUP_MEMBERS => {
+            format!("[i] Groups can have at most {} members\n", config::MAX_GROUP_MEMBERS)
+        }
+        Some(group) => {
+            group.members.push(new_member.to_string());
+            let notice = format!("[GM {}] [i] {} added {}, members: {}\n", group_id, username, new_member, group.members.join(", "));
+            for user in users_guard
+                .iter()
+                .filter(|u| u.username != username && group.members.contains(&u.username))
+            {
+                tx.send((notice.clone(), user.addr)).unwrap();
+            }
+            println!("User {} added {} to group {}", username, new_member, group_id);
+            format!("[i] Added {} to group {}\n", new_member, group_id)
+        }
+        None => format!("[i] You are not in group {}\n", group_id),
+    };
+    drop(history_guard);
+    drop(users_guard);
+
+    let _ = write_half.write_all(reply.as_bytes()).await;
+}
+
+pub(crate) async fn handle_gm_leave_command(
+    write_half: &mut WriteHalf<'_>,
+    line: &str,
+    username: &str,
+    tx: broadcast::Sender<(String, std::net::SocketAddr)>,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+    history: Arc<TokioMutex<History>>,
+) {
+    let mut parts = line.split_whitespace();
+    parts.next(); // skip /gm_leave
+    let Some(group_id) = parts.next() else {
+        let _ = write_half
+            .write_all(format!("\n{}[i] Usage: /gm_leave <group_id>{}\n\n", color_codes::YELLOW, color_codes::RESET).as_bytes())
+            .await;
+        return;
+    };
+
+    let users_guard = users.lock().await;
+    let mut history_guard = history.lock().await;
+    let reply = match history_guard.group_mut(group_id) {
+        Some(group) if group.members.iter().any(|m| m == username) => {
+            group.members.retain(|m| m != username);
+            let notice = format!("[GM {}] [i] {} left the group\n", group_id, username);
+            for user in users_guard.iter().filter(|u| group.members.contains(&u.username)) {
+                tx.send((notice.clone(), user.addr)).unwrap();
+            }
+            history_guard.remove_empty_groups();
+            println!("User {} left group {}", username, group_id);
+            format!("[i] You left group {}\n", group_id)
+        }
+        _ => format!("[i] You are not in group {}\n", group_id),
+    };
+    drop(history_guard);
+    drop(users_guard);
+
+    let _ = write_half.write_all(reply.as_bytes()).await;
+}
+
+pub(crate) async fn handle_groups_command(
+    write_half: &mut WriteHalf<'_>,
+    username: &str,
+    history: Arc<TokioMutex<History>>,
+) {
+    let history_guard = history.lock().await;
+    let mut reply = String::new();
+    for group in history_guard.groups_of(username) {
+        reply.push_str(&format!("{}: {}\n", group.id, group.members.join(", ")));
+    }
+    drop(history_guard);
+
+    if reply.is_empty() {
+        reply = "[i] You are not in any groups, start one with /gm\n".to_string();
+    }
+    let _ = write_half.write_all(reply.as_bytes()).await;
+}
+
+// tell the other side of a conversation about an edit, deletion or reaction, the caller gets a direct reply
+async fn propagate_change(
+    conversation: &Conversation,
+    change: &str,
+    username: &str,
+    addr: std::net::SocketAddr,
+    tx: broadcast::Sender<(String, std::net::SocketAddr)>,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+    history: Arc<TokioMutex<History>>,
+) {
+    let prefix = match conversation {
+        Conversation::Room(room_name) => {
+            tx.send((format!("[{}] [i] {}\n", room_name, change), addr)).unwrap();
+            return;
+        }
+        Conversation::Private(_, _) => "[PM]".to_string(),
+        Conversation::Group(id) => format!("[GM {}]", id),
+    };
+    let participants = history.lock().await.participants(conversation);
+    let users_guard = users.lock().await;
+    for user in users_guard
+        .iter()
+        .filter(|u| u.username != username && participants.contains(&u.username))
+    {
+        tx.send((format!("{} [i] {}\n", prefix, change), user.addr)).unwrap();
+    }
+}
+
+pub(crate) async fn handle_edit_command(
+    write_half: &mut WriteHalf<'_>,
+    line: &str,
+    username: &str,
+    addr: std::net::SocketAddr,
+    tx: broadcast::Sender<(String, std::net::SocketAddr)>,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+    history: Arc<TokioMutex<History>>,
+) {
+    let mut parts = line.split_whitespace();
+    parts.next(); // skip /edit
+    let id = parts.next().map(history::parse_id);
+    let text = parts.collect::<Vec<&str>>().join(" ");
+    let Some(id) = id.filter(|_| !text.is_empty()) else {
+        let _ = write_half
+            .write_all(format!("\n{}[i] Usage: /edit <message_id> <text>{}\n\n", color_codes::YELLOW, color_codes::RESET).as_bytes())
+            .await;
+        return;
+    };
+
+    let mut history_guard = history.lock().await;
+    let edited = match history_guard.find_mut(id) {
+        Some(message) if message.deleted => Err(format!("[i] Message #{} was deleted\n", id)),
+        Some(message) if message.author != username => Err("[i] You can only edit your own messages\n".to_string()),
+        Some(message) => {
+            message.text = text.clone();
+            message.edited = true;
+            Ok(message.conversation.clone())
+        }
+        None => Err(format!("[i] No message with id #{}\n", id)),
+    };
+    drop(history_guard);
+
+    let reply = match edited {
+        Ok(conversation) => {
+            let sender_name = display_name(username, users.clone()).await;
+            let change = format!("#{} edited by {}: {}", id, sender_name, text);
+            propagate_change(&conversation, &change, username, addr, tx, users, history).await;
+            println!("User {} edited message #{}", username, id);
+            format!("[i] Message #{} edited\n", id)
+        }
+        Err(reply) => reply,
+    };
+    let _ = write_half.write_all(reply.as_bytes()).await;
+}
+
+// authors can delete their messages, room operators and admins can delete any room message
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn handle_delete_command(
+    write_half: &mut WriteHalf<'_>,
+    line: &str,
+    username: &str,
+    addr: std::net::SocketAddr,
+    tx: broadcast::Sender<(String, std::net::SocketAddr)>,
+    rooms: Arc<TokioMutex<Vec<Room>>>,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+    history: Arc<TokioMutex<History>>,
+) {
+    let mut parts = line.split_whitespace();
+    parts.next(); // skip /delete
+    let Some(id) = parts.next().map(history::parse_id) else {
+        let _ = write_half
+            .write_all(format!("\n{}[i] Usage: /delete <message_id>{}\n\n", color_codes::YELLOW, color_codes::RESET).as_bytes())
+            .await;
+        return;
+    };
+
+    let is_admin = is_admin(username, users.clone()).await;
+    let mut rooms_guard = rooms.lock().await;
+    let mut history_guard = history.lock().await;
+    let deleted = match history_guard.find_mut(id) {
+        Some(message) if message.deleted => Err(format!("[i] Message #{} was already deleted\n", id)),
+        Some(message) => {
+            let is_moderator = match &message.conversation {
+                Conversation::Room(room_name) => {
+                    is_admin || rooms_guard.iter().any(|r| &r.name == room_name && r.is_operator(username))
+                }
+                _ => false,
+            };
+            if message.author == username || is_moderator {
+                message.deleted = true;
+                message.text.clear();
+                Ok(message.conversation.clone())
+            } else {
+                Err("[i] You can only delete your own messages\n".to_string())
+            }
+        }
+        None => Err(format!("[i] No message with id #{}\n", id)),
+    };
+    drop(history_guard);
+    // deleted messages don't stay pinned
+    if let Ok(Conversation::Room(room_name)) = &deleted {
+        if let Some(room) = rooms_guard.iter_mut().find(|r| &r.name == room_name) {
+            room.pinned.retain(|pinned| pinned != id);
+        }
+    }
+    drop(rooms_guard);
+
+    let reply = match deleted {
+        Ok(conversation) => {
+            let change = format!("#{} was deleted", id);
+            propagate_change(&conversation, &change, username, addr, tx, users, history).await;
+            println!("User {} deleted message #{}", username, id);
+            format!("[i] Message #{} deleted\n", id)
+        }
+        Err(reply) => reply,
+    };
+    let _ = write_half.write_all(reply.as_bytes()).await;
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn handle_react_command(
+    write_half: &mut WriteHalf<'_>,
+    line: &str,
+    username: &str,
+    addr: std::net::SocketAddr,
+    tx: broadcast::Sender<(String, std::net::SocketAddr)>,
+    rooms: Arc<TokioMutex<Vec<Room>>>,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+    history: Arc<TokioMutex<History>>,
+) {
+    update_reaction(write_half, line, true, username, addr, tx, rooms, users, history).await;
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn handle_unreact_command(
+    write_half: &mut WriteHalf<'_>,
+    line: &str,
+    username: &str,
+    addr: std::net::SocketAddr,
+    tx: broadcast::Sender<(String, std::net::SocketAddr)>,
+    rooms: Arc<TokioMutex<Vec<Room>>>,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+    history: Arc<TokioMutex<History>>,
+) {
+    update_reaction(write_half, line, false, username, addr, tx, rooms, users, history).await;
+}
+
+// reactions are sent as short events instead of room messages, so they don't get ids of their own
+#[allow(clippy::too_many_arguments)]
+async fn update_reaction(
+    write_half: &mut WriteHalf<'_>,
+    line: &str,
+    adding: bool,
+    username: &str,
+    addr: std::net::SocketAddr,
+    tx: broadcast::Sender<(String, std::net::SocketAddr)>,
+    rooms: Arc<TokioMutex<Vec<Room>>>,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+    history: Arc<TokioMutex<History>>,
+) {
+    let command = if adding { "/react" } else { "/unreact" };
+    let mut parts = line.split_whitespace();
+    parts.next(); // skip /react or /unreact
+    let (Some(id), Some(reaction)) = (parts.next().map(history::parse_id), parts.next()) else {
+        let _ = write_half
+            .write_all(format!("\n{}[i] Usage: {} <message_id> <emoji|:shortcode:>{}\n\n", color_codes::YELLOW, command, color_codes::RESET).as_bytes())
+            .await;
+        return;
+    };
+    let Some(emoji) = history::parse_reaction(reaction) else {
+        let _ = write_half
+            .write_all(format!("\n{}[i] Unknown emoji {}, use /help /react for the shortcodes{}\n\n", color_codes::RED, reaction, color_codes::RESET).as_bytes())
+            .await;
+        return;
+    };
+
+    let rooms_guard = rooms.lock().await;
+    let mut history_guard = history.lock().await;
+    let can_see = history_guard.find(id).is_some_and(|message| match &message.conversation {
+        Conversation::Room(room_name) => rooms_guard
+            .iter()
+            .any(|r| &r.name == room_name && !r.is_hidden_from(username) && r.is_member(username)),
+        conversation => history_guard.participants(conversation).iter().any(|u| u == username),
+    });
+    let updated = match history_guard.find_mut(id) {
+        Some(message) if message.deleted => Err(format!("[i] Message #{} was deleted\n", id)),
+        Some(message) => {
+            if !can_see {
+                Err(format!("[i] No message with id #{}\n", id))
+            } else if adding && !message.add_reaction(emoji, username) {
+                Err(format!("[i] You already reacted {} to #{}\n", emoji, id))
+            } else if !adding && !message.remove_reaction(emoji, username) {
+                Err(format!("[i] You have not reacted {} to #{}\n", emoji, id))
+            } else {
+                Ok((message.conversation.clone(), message.reaction_summary()))
+            }
+        }
+        None => Err(format!("[i] No message with id #{}\n", id)),
+    };
+    drop(history_guard);
+    drop(rooms_guard);
+
+    let reply = match updated {
+        Ok((conversation, summary)) => {
+            let sender_name = display_name(username, users.clone()).await;
+            let change = if adding {
+                format!("{} reacted {} to #{}{}", sender_name, emoji, id, summary)
+            } else {
+                format!("{} removed {} from #{}{}", sender_name, emoji, id, summary)
+            };
+            propagate_change(&conversation, &change, username, addr, tx, users, history).await;
+            format!("[i] #{}{}\n", id, summary)
+        }
+        Err(reply) => reply,
+    };
+    let _ = write_half.write_all(reply.as_bytes()).await;
+}
+
+pub(crate) async fn handle_mentions_command(
+    write_half: &mut WriteHalf<'_>,
+    line: &str,
+    username: &str,
+    rooms: Arc<TokioMutex<Vec<Room>>>,
+    history: Arc<TokioMutex<History>>,
+) {
+    let mut parts = line.split_whitespace();
+    parts.next(); // skip /mentions
+    let count = match parts.next().map(|count| count.parse::<usize>()) {
+        None => config::HISTORY_REPLAY_COUNT,
+        Some(Ok(count)) if count > 0 => count,
+        Some(_) => {
+            let _ = write_half
+                .write_all(format!("\n{}[i] The count must be a positive number{}\n\n", color_codes::RED, color_codes::RESET).as_bytes())
+                .await;
+            return;
+        }
+    };
+
+    let rooms_guard = rooms.lock().await;
+    let history_guard = history.lock().await;
+    // mentions can come from rooms the user isn't in, only show the ones they could open
+    let mentions: Vec<_> = history_guard
+        .mentions_of(username)
+        .filter(|message| match &message.conversation {
+            Conversation::Room(room_name) => rooms_guard
+                .iter()
+                .any(|r| r.name == *room_name && !r.is_hidden_from(username)),
+            _ => false,
+        })
+        .collect();
+    let reply = if mentions.is_empty() {
+        "[i] Nobody has mentioned you\n".to_string()
+    } else {
+        let mut reply = "[i] Recent mentions:\n".to_string();
+        for message in &mentions[mentions.len().saturating_sub(count)..] {
+            let Conversation::Room(room_name) = &message.conversation else {
+                continue;
+            };
+            reply.push_str(&format!("{} [{}] {}\n", format_timestamp(message.sent_at), room_name, message.render()));
+        }
+        reply
+    };
+    drop(history_guard);
+    drop(rooms_guard);
+
+    let _ = write_half.write_all(reply.as_bytes()).await;
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn handle_pin_command(
+    write_half: &mut WriteHalf<'_>,
+    line: &str,
+    username: &str,
+    addr: std::net::SocketAddr,
+    tx: broadcast::Sender<(String, std::net::SocketAddr)>,
+    rooms: Arc<TokioMutex<Vec<Room>>>,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+    history: Arc<TokioMutex<History>>,
+) {
+    update_pin(write_half, line, true, username, addr, tx, rooms, users, history).await;
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn handle_unpin_command(
+    write_half: &mut WriteHalf<'_>,
+    line: &str,
+    username: &str,
+    addr: std::net::SocketAddr,
+    tx: broadcast::Sender<(String, std::net::SocketAddr)>,
+    rooms: Arc<TokioMutex<Vec<Room>>>,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+    history: Arc<TokioMutex<History>>,
+) {
+    update_pin(write_half, line, false, username, addr, tx, rooms, users, history).await;
+}
+
+// only operators and admins can pin, the room is taken from the message
+#[allow(clippy::too_many_arguments)]
+async fn update_pin(
+    write_half: &mut WriteHalf<'_>,
+    line: &str,
+    pinning: bool,
+    username: &str,
+    addr: std::net::SocketAddr,
+    tx: broadcast::Sender<(String, std::net::SocketAddr)>,
+    rooms: Arc<TokioMutex<Vec<Room>>>,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+    history: Arc<TokioMutex<History>>,
+) {
+    let command = if pinning { "/pin" } else { "/unpin" };
+    let mut parts = line.split_whitespace();
+    parts.next(); // skip /pin or /unpin
+    let Some(id) = parts.next().map(history::parse_id) else {
+        let _ = write_half
+            .write_all(format!("\n{}[i] Usage: {} <message_id>{}\n\n", color_codes::YELLOW, command, color_codes::RESET).as_bytes())
+            .await;
+        return;
+    };
+
+    let is_admin = is_admin(username, users.clone()).await;
+    let mut rooms_guard = rooms.lock().await;
+    let history_guard = history.lock().await;
+    let room_name = match history_guard.find(id) {
+        Some(message) if message.deleted => Err(format!("[i] Message #{} was deleted\n", id)),
+        Some(message) => match &message.conversation {
+            Conversation::Room(room_name) => Ok(room_name.clone()),
+            _ => Err("[i] Only room messages can be pinned\n".to_string()),
+        },
+        // pinned messages can fall out of history, their pins can still be removed
+        None if !pinning => rooms_guard
+            .iter()
+            .find(|r| r.pinned.iter().any(|pinned| pinned == id) && !r.is_hidden_from(username))
+            .map(|r| r.name.clone())
+            .ok_or_else(|| format!("[i] No message with id #{}\n", id)),
+        None => Err(format!("[i] No message with id #{}\n", id)),
+    };
+
+    let mut room = room_name.and_then(|room_name| {
+        rooms_guard
+            .iter_mut()
+            .find(|r| r.name == room_name && !r.is_hidden_from(username))
+            .ok_or_else(|| format!("[i] No message with id #{}\n", id))
+    });
+    // pins of messages that are no longer in history don't count towards the limit
+    if let (Ok(room), true) = (room.as_mut(), pinning) {
+        room.pinned.retain(|pinned| history_guard.find(pinned).is_some());
+    }
+    drop(history_guard);
+    let reply = match room {
+        Ok(room) if !room.is_operator(username) && !is_admin => {
+            "[i] Only room operators can pin and unpin messages\n".to_string()
+        }
+        Ok(room) if pinning && room.pinned.iter().any(|pinned| pinned == id) => {
+            format!("[i] Message #{} is already pinned\n", id)
+        }
+        Ok(room) if pinning && room.pinned.len() >= config::MAX_PINS_PER_ROOM => {
+            format!("[i] Room {} already has {} pinned messages, unpin one first\n", room.name, config::MAX_PINS_PER_ROOM)
+        }
+        Ok(room) if !pinning && !room.pinned.iter().any(|pinned| pinned == id) => {
+            format!("[i] Message #{} is not pinned\n", id)
+        }
+        Ok(room) => {
+            let action = if pinning {
+                room.pinned.push(id.to_string());
+                "pinned"
+            } else {
+                room.pinned.retain(|pinned| pinned != id);
+                "unpinned"
+            };
+            tx.send((format!("[{}] [i] {} {} #{}\n", room.name, username, action, id), addr)).unwrap();
+            println!("User {} {} message #{} in room {}", username, action, id, room.name);
+            format!("[i] Message #{} {}\n", id, action)
+        }
+        Err(reply) => reply,
+    };
+    drop(rooms_guard);
+
+    let _ = write_half.write_all(reply.as_bytes()).await;
+}
+
+pub(crate) async fn handle_pins_command(
+    write_half: &mut WriteHalf<'_>,
+    line: &str,
+    username: &str,
+    rooms: Arc<TokioMutex<Vec<Room>>>,
+    history: Arc<TokioMutex<History>>,
+) {
+    let mut parts = line.split_whitespace();
+    parts.next(); // skip /pins
+    let Some(room_name) = parts.next() else {
+        let _ = write_half
+            .write_all(format!("\n{}[i] No room name provided{}\n\n", color_codes::YELLOW, color_codes::RESET).as_bytes())
+            .await;
+        return;
+    };
+
+    let rooms_guard = rooms.lock().await;
+    let room = rooms_guard
+        .iter()
+        .find(|r| r.name == room_name && !r.is_hidden_from(username));
+    let reply = match room {
+        Some(room) if room.is_member(username) => {
+            let pins = pinned_messages(room, history).await;
+            if pins.is_empty() {
+                format!("[i] No pinned messages in {}\n", room_name)
+            } else {
+                pins
+            }
+        }
+        Some(_) => "[i] You are not a member of this room\n".to_string(),
+        None => format!("Room {} does not exist\n", room_name),
+    };
+    drop(rooms_guard);
+
+    let _ = write_half.write_all(reply.as_bytes()).await;
+}
+
+// unread counts per room and for private messages, None when everything has been read
+pub(crate) async fn unread_summary(
+    username: &str,
+    rooms: Arc<TokioMutex<Vec<Room>>>,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+    history: Arc<TokioMutex<History>>,
+) -> Option<String> {
+    let rooms_guard = rooms.lock().await;
+    let users_guard = users.lock().await;
+    let pms_read_at = users_guard.iter().find(|u| u.username == username)?.pms_read_at;
+    let history_guard = history.lock().await;
+
+    let mut counts = vec![];
+    for room in rooms_guard.iter().filter(|r| r.is_member(username)) {
+        let Some(read_at) = room.read_marker(username) else {
+            continue;
+        };
+        let unread = history_guard.unread_in_room(&room.name, username, read_at).count();
+        if unread > 0 {
+            counts.push(format!("{}: {}", room.name, unread));
+        }
+    }
+    let unread_pms = history_guard.unread_private(username, pms_read_at).count();
+    if unread_pms > 0 {
+        counts.push(format!("PM: {}", unread_pms));
+    }
+    drop(history_guard);
+    drop(users_guard);
+    drop(rooms_guard);
+
+    (!counts.is_empty()).then(|| format!("[i] Unread messages, use /catchup <room_name> to read them:\n{}\n", counts.join("\n")))
+}
+
+pub(crate) async fn handle_unread_command(
+    write_half: &mut WriteHalf<'_>,
+    username: &str,
+    rooms: Arc<TokioMutex<Vec<Room>>>,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+    history: Arc<TokioMutex<History>>,
+) {
+    let reply = unread_summary(username, rooms, users, history)
+        .await
+        .unwrap_or_else(|| "[i] No unread messages\n".to_string());
+    let _ = write_half.write_all(reply.as_bytes()).await;
+}
+
+// replays the unread messages of a room, or of private messages with 'PM', and marks them read
+pub(crate) async fn handle_catchup_command(
+    write_half: &mut WriteHalf<'_>,
+    line: &str,
+    username: &str,
+    rooms: Arc<TokioMutex<Vec<Room>>>,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+    history: Arc<TokioMutex<History>>,
+) {
+    let mut parts = line.split_whitespace();
+    parts.next(); // skip /catchup
+    let Some(room_name) = parts.next() else {
+        let _ = write_half
+            .write_all(format!("\n{}[i] Usage: /catchup <room_name|PM>{}\n\n", color_codes::YELLOW, color_codes::RESET).as_bytes())
+            .await;
+        return;
+    };
+
+    let mut rooms_guard = rooms.lock().await;
+    let mut users_guard = users.lock().await;
+    let history_guard = history.lock().await;
+    let now = SystemTime::now();
+    let unread: Result<Vec<_>, String> = if room_name == "PM" {
+        match users_guard.iter_mut().find(|u| u.username == username) {
+            Some(user) => {
+                let unread = history_guard.unread_private(username, user.pms_read_at).collect();
+                user.pms_read_at = now;
+                Ok(unread)
+            }
+            None => Ok(vec![]),
+        }
+    } else {
+        let room = rooms_guard
+            .iter_mut()
+            .find(|r| r.name == room_name && !r.is_hidden_from(username));
+        match room {
+            Some(room) if room.is_member(username) => {
+                let read_at = room.read_marker(username).unwrap_or(now);
+                let unread = history_guard.unread_in_room(room_name, username, read_at).collect();
+                room.mark_read(username, now);
+                Ok(unread)
+            }
+            Some(_) => Err("[i] You are not a member of this room\n".to_string()),
+            None => Err(format!("Room {} does not exist\n", room_name)),
+        }
+    };
+    let reply = match unread {
+        Ok(unread) if unread.is_empty() => format!("[i] No unread messages in {}\n", room_name),
+        Ok(unread) => {
+            let skipped = unread.len().saturating_sub(config::MAX_CATCHUP_MESSAGES);
+            let mut reply = if skipped > 0 {
+                format!("[i] {} unread messages in {}, showing the last {}:\n", unread.len(), room_name, config::MAX_CATCHUP_MESSAGES)
+            } else {
+                format!("[i] {} unread messages in {}:\n", unread.len(), room_name)
+            };
+            for message in &unread[skipped..] {
+                reply.push_str(&format!("{} {}\n", format_timestamp(message.sent_at), message.render()));
+            }
+            reply
+        }
+        Err(reply) => reply,
+    };
+    drop(history_guard);
+    drop(users_guard);
+    drop(rooms_guard);
+
+    let _ = write_half.write_all(reply.as_bytes()).await;
+}
+
+pub(crate) async fn handle_search_command(
+    write_half: &mut WriteHalf<'_>,
+    line: &str,
+    username: &str,
+    rooms: Arc<TokioMutex<Vec<Room>>>,
+    history: Arc<TokioMutex<History>>,
+) {
+    let mut parts = line.split_whitespace().peekable();
+    parts.next(); // skip /search
+
+    let rooms_guard = rooms.lock().await;
+    let mut query = SearchQuery::default();
+    // the first word limits the search to a room when there is a room with that name
+    if let Some(room) = parts
+        .peek()
+        .and_then(|first| rooms_guard.iter().find(|r| r.name == *first && !r.is_hidden_from(username)))
+    {
+        query.room = Some(room.name.clone());
+        parts.next();
+    }
+    let mut invalid = None;
+    for word in parts {
+        if let Some(from) = word.strip_prefix("from:") {
+            query.from = Some(from.to_string());
+        } else if let Some(date) = word.strip_prefix("after:") {
+            query.after = parse_date(date);
+            invalid = invalid.or(query.after.is_none().then_some(word));
+        } else if let Some(date) = word.strip_prefix("before:") {
+            // the day itself is included
+            query.before = parse_date(date).map(|day| day + std::time::Duration::from_secs(24 * 60 * 60));
+            invalid = invalid.or(query.before.is_none().then_some(word));
+        } else {
+            query.terms.push(word.to_lowercase());
+        }
+    }
+    let reply = if let Some(word) = invalid {
+        format!("\n{}[i] Invalid date in {}, use YYYY-MM-DD{}\n\n", color_codes::RED, word, color_codes::RESET)
+    } else if query.is_empty() {
+        format!("\n{}[i] Usage: /search [room_name] <terms> [from:<username>] [after:<YYYY-MM-DD>] [before:<YYYY-MM-DD>]{}\n\n", color_codes::YELLOW, color_codes::RESET)
+    } else {
+        let history_guard = history.lock().await;
+        // rooms the caller is in and their own private messages
+        let can_see = |message: &history::StoredMessage| match &message.conversation {
+            Conversation::Room(room_name) => rooms_guard.iter().any(|r| &r.name == room_name && r.is_member(username)),
+            conversation => history_guard.participants(conversation).iter().any(|u| u == username),
+        };
+        let results: Vec<_> = history_guard.search(&query, can_see).take(config::MAX_SEARCH_RESULTS).collect();
+        if results.is_empty() {
+            "[i] No messages found\n".to_string()
+        } else {
+            let mut reply = "[i] Search results, newest first:\n".to_string();
+            for message in results {
+                let conversation = match &message.conversation {
+                    Conversation::Room(room_name) => room_name.clone(),
+                    Conversation::Private(sender, recipient) => format!("PM {} -> {}", sender, recipient),
+                    Conversation::Group(id) => format!("GM {}", id),
+                };
+                reply.push_str(&format!(
+                    "{} [{}] #{} [{}] {}\n",
+                    format_timestamp(message.sent_at),
+                    conversation,
+                    message.id,
+                    message.author_name,
+                    message.excerpt(query.terms.first().map(|term| term.as_str()))
+                ));
+            }
+            reply
+        }
+    };
+    drop(rooms_guard);
+
+    let _ = write_half.write_all(reply.as_bytes()).await;
+}
+
+pub(crate) async fn handle_history_command(
+    write_half: &mut WriteHalf<'_>,
+    line: &str,
+    username: &str,
+    rooms: Arc<TokioMutex<Vec<Room>>>,
+    history: Arc<TokioMutex<History>>,
+) {
+    let mut parts = line.split_whitespace();
+    parts.next(); // skip /history
+    let Some(room_name) = parts.next() else {
+        let _ = write_half
+            .write_all(format!("\n{}[i] Usage: /history <room_name> [count]{}\n\n", color_codes::YELLOW, color_codes::RESET).as_bytes())
+            .await;
+        return;
+    };
+    let count = match parts.next().map(|count| count.parse::<usize>()) {
+        None => config::HISTORY_REPLAY_COUNT,
+        Some(Ok(count)) if count > 0 => count,
+        Some(_) => {
+            let _ = write_half
+                .write_all(format!("\n{}[i] The count must be a positive number{}\n\n", color_codes::RED, color_codes::RESET).as_bytes())
+                .await;
+            return;
+        }
+    };
+
+    let rooms_guard = rooms.lock().await;
+    let room = rooms_guard
+        .iter()
+        .find(|r| r.name == room_name && !r.is_hidden_from(username));
+    let reply = match room {
+        Some(room) if room.is_member(username) => {
+            let history_guard = history.lock().await;
+            let messages: Vec<_> = history_guard.room_messages(room_name).collect();
+            if messages.is_empty() {
+                format!("[i] No messages in {} yet\n", room_name)
+            } else {
+                let mut reply = format!("[i] Last messages in {}:\n", room_name);
+                for message in &messages[messages.len().saturating_sub(count)..] {
+                    reply.push_str(&format!("{} {}\n", format_timestamp(message.sent_at), message.render()));
+                }
+                reply
+            }
+        }
+        Some(_) => "[i] You are not a member of this room\n".to_string(),
+        None => format!("Room {} does not exist\n", room_name),
+    };
+    drop(rooms_guard);
+
+    let _ = write_half.write_all(reply.as_bytes()).await;
+}
+
+// typing hints are events for machine clients, there is no reply unless something is wrong
+pub(crate) async fn handle_typing_command(
+    write_half: &mut WriteHalf<'_>,
+    line: &str,
+    username: &str,
+    addr: std::net::SocketAddr,
+    tx: broadcast::Sender<(String, std::net::SocketAddr)>,
+    rooms: Arc<TokioMutex<Vec<Room>>>,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+) {
+    let mut parts = line.split_whitespace();
+    parts.next(); // skip /typing
+    let Some(target) = parts.next() else {
+        let _ = write_half
+            .write_all(format!("\n{}[i] Usage: /typing <room_name|@username>{}\n\n", color_codes::YELLOW, color_codes::RESET).as_bytes())
+            .await;
+        return;
+    };
+
+    let error = if let Some(recipient) = target.strip_prefix('@') {
+        // private typing events are addressed to the peer like private messages
+        let users_guard = users.lock().await;
+        match users_guard.iter().find(|u| u.username == recipient) {
+            Some(recipient) => {
+                let event = format!("[event] typing PM {} {}\n", username, config::TYPING_EXPIRY_SECS);
+                tx.send((event, recipient.addr)).unwrap();
+                None
+            }
+            None => Some("User not found\n".to_string()),
+        }
+    } else {
+        let rooms_guard = rooms.lock().await;
+        let room = rooms_guard
+            .iter()
+            .find(|r| r.name == target && !r.is_hidden_from(username));
+        match room {
+            Some(room) if room.is_member(username) => {
+                let event = format!("[event] typing {} {} {}\n", target, username, config::TYPING_EXPIRY_SECS);
+                tx.send((event, addr)).unwrap();
+                None
+            }
+            Some(_) => Some("[i] You are not a member of this room\n".to_string()),
+            None => Some(format!("Room {} does not exist\n", target)),
+        }
+    };
+
+    if let Some(error) = error {
+        let _ = write_half.write_all(error.as_bytes()).await;
+    }
+}
+
+// returns the upload to collect the file's base64 lines into, if the file may be shared there
+pub(crate) async fn handle_upload_command(
+    write_half: &mut WriteHalf<'_>,
+    line: &str,
+    username: &str,
+    active_room: &str,
+    rooms: Arc<TokioMutex<Vec<Room>>>,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+) -> Option<Upload> {
+    let mut parts = line.split_whitespace();
+    parts.next(); // skip /upload
+    let Some(name) = parts.next() else {
+        let _ = write_half
+            .write_all(format!("\n{}[i] Usage: /upload <file_name> [room_name|@username]{}\n\n", color_codes::YELLOW, color_codes::RESET).as_bytes())
+            .await;
+        return None;
+    };
+    if !uploads::is_valid_name(name) {
+        let _ = write_half
+            .write_all(format!("\n{}[i] File names can only use letters, digits, '.', '_' and '-' and must end in one of: {}{}\n\n", color_codes::RED, config::UPLOAD_EXTENSIONS.join(", "), color_codes::RESET).as_bytes())
+            .await;
+        return None;
+    }
+
+    let target = parts.next().unwrap_or(active_room);
+    let target = match target.strip_prefix('@') {
+        Some(recipient) => UploadTarget::User(recipient.to_string()),
+        None => UploadTarget::Room(target.to_string()),
+    };
+    let is_admin = is_admin(username, users.clone()).await;
+    let rooms_guard = rooms.lock().await;
+    let users_guard = users.lock().await;
+    let refusal = sharing_refusal(&target, username, is_admin, &rooms_guard, &users_guard);
+    drop(users_guard);
+    drop(rooms_guard);
+
+    match refusal {
+        None => {
+            let _ = write_half
+                .write_all(format!("[i] Send {} as base64 lines, finish with /end or cancel with /cancel (max {} bytes)\n", name, config::MAX_UPLOAD_BYTES).as_bytes())
+                .await;
+            Some(Upload::new(name, target))
+        }
+        Some(reply) => {
+            let _ = write_half.write_all(reply.as_bytes()).await;
+            None
+        }
+    }
+}
+
+// why a file can't be shared with a room or user, checked when the upload starts and again
+// when it is finished since the room or the recipient may be gone by then
+fn sharing_refusal(target: &UploadTarget, username: &str, is_admin: bool, rooms: &[Room], users: &[UserInfo]) -> Option<String> {
+    match target {
+        UploadTarget::User(recipient) => {
+            (!users.iter().any(|u| u.username == *recipient)).then(|| "User not found\n".to_string())
+        }
+        UploadTarget::Room(room_name) => {
+            match rooms.iter().find(|r| r.name == *room_name && !r.is_hidden_from(username)) {
+                Some(room) if !room.is_member(username) => Some("[i] You are not a member of this room\n".to_string()),
+                Some(room) if room.read_only && !room.is_operator(username) && !is_admin => {
+                    Some(format!("[i] Room {} is read only\n", room_name))
+                }
+                Some(_) => None,
+                None => Some(format!("Room {} does not exist\n", room_name)),
+            }
+        }
+    }
+}
+
+// stores a finished upload and announces it where it was shared
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn handle_finished_upload(
+    write_half: &mut WriteHalf<'_>,
+    upload: Upload,
+    username: &str,
+    addr: std::net::SocketAddr,
+    tx: broadcast::Sender<(String, std::net::SocketAddr)>,
+    rooms: Arc<TokioMutex<Vec<Room>>>,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+    profiles: Arc<TokioMutex<Vec<Profile>>>,
+    history: Arc<TokioMutex<History>>,
+    uploads: Arc<TokioMutex<Uploads>>,
+) {
+    let data = match uploads::decode_base64(&upload.encoded) {
+        Some(data) if data.is_empty() => Err("[i] The file is empty, nothing was shared".to_string()),
+        Some(data) if data.len() > config::MAX_UPLOAD_BYTES => {
+            Err(format!("[i] The file is larger than {} bytes, nothing was shared", config::MAX_UPLOAD_BYTES))
+        }
+        Some(data) => Ok(data),
+        None => Err("[i] The file is not valid base64, nothing was shared".to_string()),
+    };
+    let data = match data {
+        Ok(data) => data,
+        Err(reply) => {
+            let _ = write_half.write_all(format!("{}{}{}\n", color_codes::RED, reply, color_codes::RESET).as_bytes()).await;
+            return;
+        }
+    };
+
+    // the rooms stay locked until the file is stored, so a room deleted in the meantime can't
+    // miss it in close_room and leave it to a new room with the same name
+    let is_admin = is_admin(username, users.clone()).await;
+    let rooms_guard = rooms.lock().await;
+    let users_guard = users.lock().await;
+    let refusal = sharing_refusal(&upload.target, username, is_admin, &rooms_guard, &users_guard);
+    drop(users_guard);
+    if let Some(reply) = refusal {
+        drop(rooms_guard);
+        let _ = write_half
+            .write_all(format!("{}{}[i] {} was not shared{}\n", reply, color_codes::RED, upload.name, color_codes::RESET).as_bytes())
+            .await;
+        return;
+    }
+
+    let mut uploads_guard = uploads.lock().await;
+    if !uploads_guard.has_room_for(data.len()) {
+        drop(uploads_guard);
+        drop(rooms_guard);
+        let _ = write_half
+            .write_all(format!("{}[i] The server has no space left for uploads{}\n", color_codes::RED, color_codes::RESET).as_bytes())
+            .await;
+        return;
+    }
+    let id = uploads_guard.next_id();
+    // written while holding the lock so two uploads can't both take the last free space
+    let stored = match tokio::fs::create_dir_all(config::UPLOAD_DIR).await {
+        Ok(()) => tokio::fs::write(uploads::path(&id), &data).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = stored {
+        drop(uploads_guard);
+        drop(rooms_guard);
+        println!("Failed to store upload {} from {}: {}", id, username, e);
+        let _ = write_half
+            .write_all(format!("{}[i] The file could not be stored{}\n", color_codes::RED, color_codes::RESET).as_bytes())
+            .await;
+        return;
+    }
+    uploads_guard.add(SharedFile {
+        id: id.clone(),
+        name: upload.name.clone(),
+        uploader: username.to_string(),
+        conversation: upload.target.conversation(username),
+        size: data.len(),
+        uploaded_at: SystemTime::now(),
+    });
+    drop(uploads_guard);
+    drop(rooms_guard);
+    println!("User {} uploaded {} as {} ({} bytes)", username, upload.name, id, data.len());
+
+    let announcement = format!("shared {} ({} bytes), use /download {} to get it", upload.name, data.len(), id);
+    match &upload.target {
+        UploadTarget::User(recipient) => {
+            handle_pm_command(write_half, recipient, &announcement, username, tx, users, history).await;
+        }
+        UploadTarget::Room(room_name) => {
+            send_room_message(write_half, room_name, &announcement, None, username, addr, tx, rooms, users, profiles, history).await;
+        }
+    }
+}
+
+pub(crate) async fn handle_download_command(
+    write_half: &mut WriteHalf<'_>,
+    line: &str,
+    username: &str,
+    rooms: Arc<TokioMutex<Vec<Room>>>,
+    uploads: Arc<TokioMutex<Uploads>>,
+) {
+    let mut parts = line.split_whitespace();
+    parts.next(); // skip /download
+    let Some(id) = parts.next() else {
+        let _ = write_half
+            .write_all(format!("\n{}[i] Usage: /download <file_id>{}\n\n", color_codes::YELLOW, color_codes::RESET).as_bytes())
+            .await;
+        return;
+    };
+
+    let rooms_guard = rooms.lock().await;
+    let uploads_guard = uploads.lock().await;
+    // files look missing to anyone outside the conversation they were shared in
+    let file = uploads_guard.find(id).filter(|file| {
+        file.uploader == username
+            || match &file.conversation {
+                Conversation::Room(room_name) => rooms_guard.iter().any(|r| &r.name == room_name && r.is_member(username)),
+                Conversation::Private(sender, recipient) => sender == username || recipient == username,
+                Conversation::Group(_) => false,
+            }
+    });
+    let file = file.map(|file| (file.name.clone(), file.uploader.clone(), file.uploaded_at));
+    drop(uploads_guard);
+    drop(rooms_guard);
+
+    let Some((name, uploader, uploaded_at)) = file else {
+        let _ = write_half.write_all(format!("[i] No file with id {}\n", id).as_bytes()).await;
+        return;
+    };
+    let data = match tokio::fs::read(uploads::path(id)).await {
+        Ok(data) => data,
+        Err(e) => {
+            println!("Failed to read upload {}: {}", id, e);
+            let _ = write_half.write_all(format!("[i] File {} is no longer available\n", id).as_bytes()).await;
+            return;
+        }
+    };
+
+    let mut reply = format!(
+        "[i] File {} {} ({} bytes) shared by {} on {}, base64 follows until the end line:\n",
+        id,
+        name,
+        data.len(),
+        uploader,
+        format_timestamp(uploaded_at)
+    );
+    let encoded = uploads::encode_base64(&data);
+    // every chunk is ascii, so splitting the bytes keeps valid strings
+    for chunk in encoded.as_bytes().chunks(76) {
+        reply.push_str(std::str::from_utf8(chunk).unwrap());
+        reply.push('\n');
+    }
+    reply.push_str(&format!("[i] End of file {}\n", id));
+    let _ = write_half.write_all(reply.as_bytes()).await;
+}
+
+pub(crate) async fn handle_away_command(
+    write_half: &mut WriteHalf<'_>,
+    line: &str,
+    username: &str,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+) {
+    let mut parts = line.split_whitespace();
+    parts.next(); // skip /away
+    let message = parts.collect::<Vec<&str>>().join(" ");
+    let message = if message.is_empty() { "Away".to_string() } else { message };
+
+    let mut users_guard = users.lock().await;
+    if let Some(user) = users_guard.iter_mut().find(|u| u.username == username) {
+        user.away = Some(Away {
+            message: message.clone(),
+            since: Instant::now(),
+            automatic: false,
+        });
+    }
+    drop(users_guard);
+    println!("User {} is away: {}", username, message);
+    let _ = write_half
+        .write_all(format!("{}[i] You are now marked as away: {}{}\n", color_codes::YELLOW, message, color_codes::RESET).as_bytes())
+        .await;
+}
+
+pub(crate) async fn handle_back_command(
+    write_half: &mut WriteHalf<'_>,
+    username: &str,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+) {
+    let mut users_guard = users.lock().await;
+    let was_away = match users_guard.iter_mut().find(|u| u.username == username) {
+        Some(user) => user.away.take().is_some(),
+        None => false,
+    };
+    drop(users_guard);
+    if was_away {
+        println!("User {} is back", username);
+        let _ = write_half
+            .write_all(format!("{}[i] You are no longer marked as away{}\n", color_codes::GREEN, color_codes::RESET).as_bytes())
+            .await;
+    } else {
+        let _ = write_half
+            .write_all(b"[i] You are not marked as away\n")
+            .await;
+    }
+}
+
+pub(crate) async fn handle_nick_command(
+    write_half: &mut WriteHalf<'_>,
+    line: &str,
+    username: &str,
+    addr: std::net::SocketAddr,
+    tx: broadcast::Sender<(String, std::net::SocketAddr)>,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+    profiles: Arc<TokioMutex<Vec<Profile>>>,
+) {
+    let mut parts = line.split_whitespace();
+    parts.next(); // skip /nick
+    let new_nick = parts.next();
+
+    if let Some(nick) = new_nick {
+        if nick.len() > config::MAX_NICK_LENGTH || nick.contains(['[', ']']) {
+            let _ = write_half
+                .write_all(format!("\n{}[i] Nicknames can be at most {} characters and cannot contain brackets{}\n\n", color_codes::RED, config::MAX_NICK_LENGTH, color_codes::RESET).as_bytes())
+                .await;
+            return;
+        }
+    }
+
+    let mut users_guard = users.lock().await;
+
+    // a nick may not look like anyone else's nick or the username of anyone who ever logged in
+    if let Some(nick) = new_nick {
+        let profiles_guard = profiles.lock().await;
+        let taken = profiles_guard
+            .iter()
+            .any(|p| p.username != username && p.username.eq_ignore_ascii_case(nick))
+            || users_guard.iter().any(|u| {
+                u.username != username && u.nick.as_deref().is_some_and(|n| n.eq_ignore_ascii_case(nick))
+            });
+        drop(profiles_guard);
+        if taken {
+            drop(users_guard);
+            let _ = write_half
+                .write_all(format!("\n{}[i] Nickname {} is already in use{}\n\n", color_codes::RED, nick, color_codes::RESET).as_bytes())
+                .await;
+            return;
+        }
+    }
+
+    let user = users_guard.iter_mut().find(|u| u.username == username);
+    let Some(user) = user else {
+        return;
+    };
+    let old_name = user.display_name().to_string();
+    user.nick = new_nick.map(|nick| nick.to_string());
+    let new_name = user.display_name().to_string();
+
+    // let everyone sharing a room with the user know about the change
+    for room_name in user.rooms.iter() {
+        let msg = format!("[{}] [i] {} is now known as {}\n", room_name, old_name, new_name);
+        tx.send((msg, addr)).unwrap();
+    }
+    drop(users_guard);
+
+    println!("User {} changed display name from {} to {}", username, old_name, new_name);
+    let _ = write_half
+        .write_all(format!("[i] You are now known as {}\n", new_name).as_bytes())
+        .await;
+}
+
+pub(crate) async fn handle_profile_command(
+    write_half: &mut WriteHalf<'_>,
+    line: &str,
+    username: &str,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+    profiles: Arc<TokioMutex<Vec<Profile>>>,
+) {
+    let mut parts = line.split_whitespace();
+    parts.next(); // skip /profile
+    let action = parts.next();
+    let field = parts.next();
+    let value = parts.collect::<Vec<&str>>().join(" ");
+
+    let Some(action) = action else {
+        // no arguments shows your own profile
+        let users_guard = users.lock().await;
+        let profiles_guard = profiles.lock().await;
+        let whois = whois_text(username, username, &users_guard, &profiles_guard);
+        drop(profiles_guard);
+        drop(users_guard);
+        let _ = write_half.write_all(whois.as_bytes()).await;
+        return;
+    };
+
+    if action != "set" && action != "clear" {
+        let _ = write_half
+            .write_all(format!("\n{}[i] Usage: /profile [set|clear] <bio|pronouns|timezone|status> [text]{}\n\n", color_codes::YELLOW, color_codes::RESET).as_bytes())
+            .await;
+        return;
+    }
+
+    let new_value = if action == "set" {
+        if value.is_empty() {
+            let _ = write_half
+                .write_all(format!("\n{}[i] No value provided{}\n\n", color_codes::YELLOW, color_codes::RESET).as_bytes())
+                .await;
+            return;
+        }
+        if value.len() > config::MAX_PROFILE_FIELD_LENGTH {
+            let _ = write_half
+                .write_all(format!("\n{}[i] Profile fields can be at most {} characters{}\n\n", color_codes::RED, config::MAX_PROFILE_FIELD_LENGTH, color_codes::RESET).as_bytes())
+                .await;
+            return;
+        }
+        Some(value)
+    } else {
+        None
+    };
+
+    let mut profiles_guard = profiles.lock().await;
+    let Some(profile) = profiles_guard.iter_mut().find(|p| p.username == username) else {
+        return;
+    };
+    let slot = match field {
+        Some("bio") => &mut profile.bio,
+        Some("pronouns") => &mut profile.pronouns,
+        Some("timezone") => &mut profile.timezone,
+        Some("status") => &mut profile.status,
+        _ => {
+            drop(profiles_guard);
+            let _ = write_half
+                .write_all(format!("\n{}[i] Profile fields are bio, pronouns, timezone and status{}\n\n", color_codes::YELLOW, color_codes::RESET).as_bytes())
+                .await;
+            return;
+        }
+    };
+    *slot = new_value;
+    drop(profiles_guard);
+
+    println!("User {} updated their profile", username);
+    let _ = write_half
+        .write_all(b"[i] Profile updated\n")
+        .await;
+}
+
+pub(crate) async fn handle_whois_command(
+    write_half: &mut WriteHalf<'_>,
+    line: &str,
+    username: &str,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+    profiles: Arc<TokioMutex<Vec<Profile>>>,
+) {
+    let mut parts = line.split_whitespace();
+    parts.next(); // skip /whois
+    let Some(target) = parts.next() else {
+        let _ = write_half
+            .write_all(format!("\n{}[i] No username provided{}\n\n", color_codes::YELLOW, color_codes::RESET).as_bytes())
+            .await;
+        return;
+    };
+
+    let users_guard = users.lock().await;
+    let profiles_guard = profiles.lock().await;
+
+    // accept a nick as well, but usernames take priority
+    let target_username = if profiles_guard.iter().any(|p| p.username == target) {
+        Some(target.to_string())
+    } else {
+        users_guard
+            .iter()
+            .find(|u| u.nick.as_deref() == Some(target))
+            .map(|u| u.username.clone())
+    };
+
+    let reply = match target_username {
+        Some(target_username) => whois_text(&target_username, username, &users_guard, &profiles_guard),
+        None => format!("User {} does not exist\n", target),
+    };
+    drop(profiles_guard);
+    drop(users_guard);
+
+    let _ = write_half.write_all(reply.as_bytes()).await;
+}
+
+// profile and session details of a user as seen by the caller
+fn whois_text(target: &str, caller: &str, users: &[UserInfo], profiles: &[Profile]) -> String {
+    let mut text = format!("\n{}[i] whois {}{}\n", color_codes::GREEN, target, color_codes::RESET);
+
+    if let Some(profile) = profiles.iter().find(|p| p.username == target) {
+        let fields = [
+            ("Pronouns", &profile.pronouns),
+            ("Timezone", &profile.timezone),
+            ("Status", &profile.status),
+            ("Bio", &profile.bio),
+        ];
+        for (label, value) in fields {
+            if let Some(value) = value {
+                text.push_str(&format!("  {}: {}\n", label, value));
+            }
+        }
+        text.push_str(&format!("  Registered: {}\n", format_timestamp(profile.registered_at)));
+    }
+
+    let caller_info = users.iter().find(|u| u.username == caller);
+    match users.iter().find(|u| u.username == target) {
+        Some(user) => {
+            if let Some(nick) = &user.nick {
+                text.push_str(&format!("  Nick: {}\n", nick));
+            }
+            text.push_str(&format!("  Online for: {}\n", format_duration(user.connected_at.elapsed())));
+            text.push_str(&format!("  Idle: {}\n", format_duration(user.last_active.elapsed())));
+            text.push_str(&format!("  Presence: {}\n", user.presence()));
+            if let Some(latency) = user.latency {
+                text.push_str(&format!("  Latency: {}\n", latency::format_latency(latency)));
+            }
+            if let Some(caller_info) = caller_info {
+                let shared_rooms: Vec<&str> = user
+                    .rooms
+                    .iter()
+                    .filter(|r| caller_info.rooms.contains(r))
+                    .map(|r| r.as_str())
+                    .collect();
+                if !shared_rooms.is_empty() {
+                    text.push_str(&format!("  Shared rooms: {}\n", shared_rooms.join(", ")));
+                }
+                if caller_info.role == Role::Admin {
+                    text.push_str(&format!("  Address: {}\n", user.addr));
+                }
+            }
+        }
+        None => text.push_str("  Offline\n"),
+    }
+
+    text.push('\n');
+    text
+}
+
+pub(crate) async fn handle_help_command(write_half: &mut WriteHalf<'_>, line: &str) {
+    let mut parts = line.split_whitespace();
+    parts.next(); // skip /help
+
+    for command in parts {
+        match command {
+            "/create_room" => {
+                let _ = write_half.write_all(format!("{}\n/create_room <room_name> [public|unlisted|invite_only] [persistent|ephemeral|ttl:<minutes>] - Create a new chat room.\nUse an underscore between multi-word room names.\nRoom names cannot contain brackets, and {} are reserved.\nUnlisted rooms are hidden from /view_rooms, invite only rooms also need an invite to join.\nEphemeral rooms are deleted when the last member leaves, ttl rooms after the given minutes without activity.\nRooms are deleted after a day without activity unless another lifecycle is given.\n{}\n", color_codes::YELLOW, config::RESERVED_ROOM_NAMES.join(", "), color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/join_room" => {
+                let _ = write_half.write_all(format!("{}\n/join_room <room_name> - Join an existing chat room.\nYou must provide a valid room name.\nUse '/view_rooms' to list available rooms.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/view_rooms" => {
+                let _ = write_half.write_all(format!("{}\n/view_rooms [pattern] [page:<n>] - View chat rooms, most active first.\nShows member counts, topics and the rooms you have joined.\nA pattern filters room names, use '*' as a wildcard.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/leave_room" => {
+                let _ = write_half.write_all(format!("{}\n/leave_room <room_name> - Leave a chat room.\nYou must be a member of the room to leave it.\nLeave 'glb' to stop receiving global messages, '/join_room glb' to get them again.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/m_room" => {
+                let _ = write_half.write_all(format!("{}\n/m_room <room_name> <message> - Send a message to all users in a specific room.\nYou must be a member of the room to send a message.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/topic" => {
+                let _ = write_half.write_all(format!("{}\n/topic <room_name> [text] - Set the topic of a room.\nOnly room operators can change the topic, leave the text out to clear it.\nMembers are told when the topic changes.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/describe" => {
+                let _ = write_half.write_all(format!("{}\n/describe <room_name> [text] - Set the description of a room.\nOnly room operators can change the description, leave the text out to clear it.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/visibility" => {
+                let _ = write_half.write_all(format!("{}\n/visibility <room_name> <public|unlisted|invite_only> - Change who can see and join a room.\nOnly room operators can change the visibility.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/room_info" => {
+                let _ = write_half.write_all(format!("{}\n/room_info <room_name> - Show a room's topic, visibility, lifecycle, members and operators.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/capacity" => {
+                let _ = write_half.write_all(format!("{}\n/capacity <room_name> <max_members|none> - Limit how many members a room can have.\nOnly room operators can change the capacity.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/waitlist" => {
+                let _ = write_half.write_all(format!("{}\n/waitlist <room_name> [cancel] - Wait for a spot in a full room.\nYou join the room automatically when it is your turn.\nUse 'cancel' to leave the waitlist.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/invite" => {
+                let _ = write_half.write_all(format!("{}\n/invite <username> <room_name> - Invite a user to a room.\nYou must be a member or operator of the room.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/invites" => {
+                let _ = write_half.write_all(format!("{}\n/invites - List the rooms you have pending invites to.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/accept" => {
+                let _ = write_half.write_all(format!("{}\n/accept <room_name> - Accept an invite and join the room.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/decline" => {
+                let _ = write_half.write_all(format!("{}\n/decline <room_name> - Decline an invite to a room.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/read_only" => {
+                let _ = write_half.write_all(format!("{}\n/read_only <room_name> <on|off> - Only let operators post in a room, e.g. for announcements.\nAdmins can use this on the global room '{}'.\n{}\n", color_codes::YELLOW, config::GLOBAL_ROOM, color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/say" => {
+                let _ = write_half.write_all(format!("{}\n/say <room_name> <message> - Send a message to a room without switching to it.\nSame as /m_room.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/switch" | "/focus" => {
+                let _ = write_half.write_all(format!("{}\n/switch [room_name] - Send your plain messages to a room instead of '{}'.\nYou must be a member of the room. Without a room name it shows where plain messages go.\n/focus works the same way.\n{}\n", color_codes::YELLOW, config::GLOBAL_ROOM, color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/paste" => {
+                let _ = write_half.write_all(format!("{}\n/paste [room_name] - Send the following lines as one message, finish with /end.\nWithout a room name the message goes to the room plain messages go to.\nYou can also start and finish a multi-line message with a line containing ```.\nUse /cancel to throw the message away.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/prompt" => {
+                let _ = write_half.write_all(format!("{}\n/prompt <on|off> - Show the room your plain messages go to before each line.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/view_users" => {
+                let _ = write_half.write_all(format!("{}\n/view_users <room_name> - View users in a specific chat room.\nYou must be a member of the room to view its users.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/list" => {
+                let _ = write_half.write_all(format!("{}\n/list - List all connected users and whether they are online, idle or away.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/report" => {
+                let _ = write_half.write_all(format!("{}\n/report <username> - Report a user to the server admin.\nYou must provide a valid username.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/pm" => {
+                let _ = write_half.write_all(format!("{}\n/pm <username> <message> - Send a private message to any connected user.\nYou must provide a valid username and a message.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/edit" => {
+                let _ = write_half.write_all(format!("{}\n/edit <message_id> <text> - Change the text of a message you sent.\nEvery message gets an id like #1k, it is shown next to the message.\nCurrent recipients see the edit.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/delete" => {
+                let _ = write_half.write_all(format!("{}\n/delete <message_id> - Delete a message you sent.\nRoom operators and admins can delete any message in a room.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/reply" => {
+                let _ = write_half.write_all(format!("{}\n/reply <message_id> <text> - Reply to a room message.\nThe reply is sent to the message's room with a short quote of it and joins its thread.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/thread" => {
+                let _ = write_half.write_all(format!("{}\n/thread <message_id> - Show a message and all replies in its thread.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/thread_replies" => {
+                let _ = write_half.write_all(format!("{}\n/thread_replies <show|hide> - Show or hide replies in your room messages.\nReplies to threads you started are always shown, use /thread to read the others.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/react" => {
+                let shortcodes: Vec<&str> = config::EMOJI_SHORTCODES.iter().map(|(code, _)| *code).collect();
+                let _ = write_half.write_all(format!("{}\n/react <message_id> <emoji|:shortcode:> - React to a message.\nMembers of the room are told about the reaction, it is also shown with the message in /history.\nShortcodes: {}\n{}\n", color_codes::YELLOW, shortcodes.join(" "), color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/unreact" => {
+                let _ = write_half.write_all(format!("{}\n/unreact <message_id> <emoji|:shortcode:> - Remove your reaction from a message.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/mentions" => {
+                let _ = write_half.write_all(format!("{}\n/mentions [count] - Show the last messages that mentioned you, {} by default.\nMention someone by writing @username in a room message, you get a bell and a highlight when you are mentioned.\nMentions made while you were offline or outside the room are kept too.\nRoom operators can use @room for every member and @here for members who are online and not away.\n{}\n", color_codes::YELLOW, config::HISTORY_REPLAY_COUNT, color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/pin" => {
+                let _ = write_half.write_all(format!("{}\n/pin <message_id> - Pin a message in its room, e.g. links or rules.\nOnly room operators can pin, a room can have up to {} pinned messages.\nPinned messages are shown to users when they join the room.\n{}\n", color_codes::YELLOW, config::MAX_PINS_PER_ROOM, color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/unpin" => {
+                let _ = write_half.write_all(format!("{}\n/unpin <message_id> - Unpin a message.\nOnly room operators can unpin.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/pins" => {
+                let _ = write_half.write_all(format!("{}\n/pins <room_name> - List the pinned messages of a room.\nYou must be a member of the room.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/unread" => {
+                let _ = write_half.write_all(format!("{}\n/unread - Show how many messages you have not read in each room and in private messages.\nMessages count as read when they arrive in the room your plain messages go to while you are not away.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/catchup" => {
+                let _ = write_half.write_all(format!("{}\n/catchup <room_name|PM> - Show the messages you have not read in a room and mark them read.\nUse 'PM' for your private messages.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/search" => {
+                let _ = write_half.write_all(format!("{}\n/search [room_name] <terms> [from:<username>] [after:<YYYY-MM-DD>] [before:<YYYY-MM-DD>] - Search stored messages.\nSearches the rooms you are in and your private messages, newest first.\nAll terms have to appear in a message, dates are in UTC and include the given days.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/history" => {
+                let _ = write_half.write_all(format!("{}\n/history <room_name> [count] - Show the last messages sent to a room, {} by default.\nYou must be a member of the room.\n{}\n", color_codes::YELLOW, config::HISTORY_REPLAY_COUNT, color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/machine" => {
+                let _ = write_half.write_all(format!("{}\n/machine <on|off> - Mark this session as a program rather than a person.\nMachine sessions also get event lines, e.g. '[event] typing <room_name|PM> <username> <expires_in_secs>'.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/typing" => {
+                let _ = write_half.write_all(format!("{}\n/typing <room_name|@username> - Tell a room or a user that you are typing.\nOnly machine sessions see the hint, it runs out after {} seconds so repeat it while typing.\n{}\n", color_codes::YELLOW, config::TYPING_EXPIRY_SECS, color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/gm" => {
+                let _ = write_half.write_all(format!("{}\n/gm <username,username,...|group_id> <message> - Send a message to a group of users.\nThe first message to a new set of users starts a group with a stable id, e.g. g1.\nGroups are private to their members and are never listed like rooms.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/gm_add" => {
+                let _ = write_half.write_all(format!("{}\n/gm_add <group_id> <username> - Add a user to a group you are in.\nGroups can have up to {} members.\n{}\n", color_codes::YELLOW, config::MAX_GROUP_MEMBERS, color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/gm_leave" => {
+                let _ = write_half.write_all(format!("{}\n/gm_leave <group_id> - Leave a group.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/groups" => {
+                let _ = write_half.write_all(format!("{}\n/groups - List the groups you are in and their members.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/upload" => {
+                let _ = write_half.write_all(format!("{}\n/upload <file_name> [room_name|@username] - Share a file with a room or a user.\nSend the file as base64 on the following lines and finish with /end, /cancel throws it away.\nWithout a room name the file goes to the room plain messages go to.\nFiles can be up to {} bytes and must end in one of: {}\n{}\n", color_codes::YELLOW, config::MAX_UPLOAD_BYTES, config::UPLOAD_EXTENSIONS.join(", "), color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/download" => {
+                let _ = write_half.write_all(format!("{}\n/download <file_id> - Get a shared file as base64.\nOnly the room or users the file was shared with can download it.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/away" => {
+                let _ = write_half.write_all(format!("{}\n/away [message] - Mark yourself as away.\nUsers who private message you get your away message as a reply.\nUse '/back' to clear it.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/back" => {
+                let _ = write_half.write_all(format!("{}\n/back - Clear your away status.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/nick" => {
+                let _ = write_half.write_all(format!("{}\n/nick [display_name] - Set the name shown next to your messages.\nOther users still use your username for commands like /pm.\nIt can't be anyone else's nickname or the username of anyone who has logged in before.\nUse '/nick' without a name to go back to your username.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/profile" => {
+                let _ = write_half.write_all(format!("{}\n/profile - Show your profile.\n/profile set <field> <text> - Set a profile field.\n/profile clear <field> - Clear a profile field.\nFields are bio, pronouns, timezone and status.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/whois" => {
+                let _ = write_half.write_all(format!("{}\n/whois <username> - Show a user's profile, online and idle time and the rooms you share.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/ping" => {
+                let _ = write_half.write_all(format!("{}\n/ping - Show your latency to the server.\nThe round trip time is measured by the server's network stack, nothing has to be answered.\nIt is also refreshed in the background and shown in /whois.\n{}\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            "/exit" => {
+                let _ = write_half.write_all(format!("{}\n/exit - Disconnect from the server{}\n\n", color_codes::YELLOW,color_codes::RESET).as_bytes())
+                    .await;
+            }
+            _ => {
+                let _ = write_half.write_all(format!("{}\nNo such command{}\n\n", color_codes::RED, color_codes::RESET).as_bytes()).await;
+                break;
+            }
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pattern_without_wildcard_matches_anywhere() {
+        assert!(matches_pattern("rust_help", "help"));
+        assert!(matches_pattern("rust_help", "rust_help"));
+        assert!(!matches_pattern("rust_help", "go"));
+    }
+
+    #[test]
+    fn wildcards_anchor_the_ends() {
+        assert!(matches_pattern("rust_help", "rust*"));
+        assert!(!matches_pattern("my_rust_help", "rust*"));
+        assert!(matches_pattern("rust_help", "*help"));
+        assert!(!matches_pattern("rust_help_old", "*help"));
+        assert!(matches_pattern("rust_help", "r*_*p"));
+        assert!(!matches_pattern("rust_help", "r*x*p"));
+        assert!(matches_pattern("anything", "*"));
+    }
+
+    #[test]
+    fn wildcard_pieces_do_not_overlap() {
+        assert!(!matches_pattern("ab", "ab*b"));
+        assert!(matches_pattern("abb", "ab*b"));
+    }
+}
@@ -1,4 +1,5 @@
 pub const GREEN: &str = "\x1b[32m";
 pub const YELLOW: &str = "\x1b[33m";
 pub const RED: &str = "\x1b[31m";
-pub const RESET: &str = "\x1b[0m";
\ No newline at end of file
+pub const RESET: &str = "\x1b[0m";
+pub const BELL: &str = "\x07";
\ No newline at end of file
@@ -0,0 +1,170 @@
+// Keep a user's room memberships when they disconnect so they are put back
+// into the same rooms when they reconnect under the same username.
+// There are no passwords yet, so anyone reconnecting with that name inherits them.
+pub const KEEP_ROOMS_ON_DISCONNECT: bool = false;
+
+// Connections from these addresses are given the admin role
+pub const ADMIN_IPS: &[&str] = &["127.0.0.1", "::1"];
+
+// Longest line (in bytes, including the newline) a client may send
+pub const MAX_LINE_LENGTH: usize = 1024;
+
+// Capacity of the broadcast channel shared by all sessions
+pub const BROADCAST_CAPACITY: usize = 256;
+
+// Token bucket settings used by the per-session rate limiter
+#[derive(Debug)]
+pub struct RateLimits {
+    pub messages_per_sec: f64,
+    pub message_burst: f64,
+    pub commands_per_sec: f64,
+    pub command_burst: f64,
+    pub bytes_per_sec: f64,
+    pub byte_burst: f64,
+    // base64 lines of /upload, kept apart from chat so a file doesn't use up the byte bucket
+    pub upload_bytes_per_sec: f64,
+    pub upload_byte_burst: f64,
+    // lines inside /paste and ``` blocks, the finished message is charged like any other
+    pub paste_lines_per_sec: f64,
+    pub paste_line_burst: f64,
+}
+
+pub const USER_RATE_LIMITS: RateLimits = RateLimits {
+    messages_per_sec: 2.0,
+    message_burst: 5.0,
+    commands_per_sec: 3.0,
+    command_burst: 10.0,
+    bytes_per_sec: 1024.0,
+    byte_burst: 4096.0,
+    upload_bytes_per_sec: 16384.0,
+    upload_byte_burst: 2097152.0,
+    paste_lines_per_sec: 10.0,
+    paste_line_burst: 250.0,
+};
+
+pub const ADMIN_RATE_LIMITS: RateLimits = RateLimits {
+    messages_per_sec: 10.0,
+    message_burst: 20.0,
+    commands_per_sec: 10.0,
+    command_burst: 30.0,
+    bytes_per_sec: 8192.0,
+    byte_burst: 16384.0,
+    upload_bytes_per_sec: 65536.0,
+    upload_byte_burst: 4194304.0,
+    paste_lines_per_sec: 50.0,
+    paste_line_burst: 1000.0,
+};
+
+// Rate limit strikes before a user is muted, and before they are disconnected
+pub const MUTE_STRIKES: u32 = 5;
+pub const DISCONNECT_STRIKES: u32 = 15;
+pub const MUTE_SECS: u64 = 30;
+// Strikes are forgotten after this many seconds without a new one
+pub const STRIKE_RESET_SECS: u64 = 60;
+
+// Limits applied in the accept loop before a client gets to pick a username
+pub const MAX_CONNECTIONS: usize = 100;
+pub const MAX_CONNECTIONS_PER_IP: usize = 5;
+pub const MAX_ACCEPTS_PER_IP: usize = 10;
+pub const ACCEPT_WINDOW_SECS: u64 = 60;
+// Seconds a new connection has to send its username
+pub const LOGIN_TIMEOUT_SECS: u64 = 60;
+
+// Idle users are marked away after IDLE_AWAY_SECS and disconnected after IDLE_DISCONNECT_SECS
+pub const IDLE_AWAY_SECS: u64 = 10 * 60;
+pub const IDLE_DISCONNECT_SECS: u64 = 60 * 60;
+// How often each session checks whether its user has gone idle
+pub const IDLE_CHECK_SECS: u64 = 15;
+// Users without activity for this long are shown as idle in /list and /view_users
+pub const SHOW_IDLE_AFTER_SECS: u64 = 2 * 60;
+
+// TCP keepalive on accepted sockets, so dead peers are noticed even when nobody writes to them
+pub const KEEPALIVE_TIME_SECS: u64 = 60;
+pub const KEEPALIVE_INTERVAL_SECS: u64 = 10;
+
+// Longest display name accepted by /nick
+pub const MAX_NICK_LENGTH: usize = 32;
+
+// Longest username accepted at login
+pub const MAX_USERNAME_LENGTH: usize = 32;
+
+// Longest value accepted for a /profile field
+pub const MAX_PROFILE_FIELD_LENGTH: usize = 200;
+
+// Rooms shown per page of /view_rooms
+pub const ROOMS_PER_PAGE: usize = 20;
+
+// Room names that would clash with the prefixes used to route messages
+pub const RESERVED_ROOM_NAMES: &[&str] = &["glb", "adm", "PM", "GM", "i", "notice", "event"];
+
+// Rooms created without a lifecycle option are deleted after this long without activity
+pub const DEFAULT_ROOM_TTL_SECS: u64 = 24 * 60 * 60;
+// Ephemeral rooms that nobody joins are deleted after this long
+pub const EPHEMERAL_ROOM_GRACE_SECS: u64 = 10 * 60;
+// How often expired rooms are looked for
+pub const ROOM_SWEEP_SECS: u64 = 60;
+
+// Room every user joins on connect, plain lines are sent here
+pub const GLOBAL_ROOM: &str = "glb";
+
+// Limits for multi-line messages sent with /paste or ``` fences
+pub const MAX_PASTE_LINES: usize = 200;
+pub const MAX_PASTE_BYTES: usize = 16 * 1024;
+
+// Messages kept in memory for /history, /edit and /delete, oldest are dropped first
+pub const MAX_HISTORY_MESSAGES: usize = 5000;
+// Messages shown by /history when no count is given
+pub const HISTORY_REPLAY_COUNT: usize = 20;
+// Characters of the parent message quoted in a reply
+pub const REPLY_SNIPPET_CHARS: usize = 40;
+
+// Shortcodes accepted by /react, emoji can also be typed directly
+pub const EMOJI_SHORTCODES: &[(&str, &str)] = &[
+    (":+1:", "👍"),
+    (":thumbsup:", "👍"),
+    (":-1:", "👎"),
+    (":thumbsdown:", "👎"),
+    (":heart:", "❤️"),
+    (":smile:", "😄"),
+    (":joy:", "😂"),
+    (":tada:", "🎉"),
+    (":eyes:", "👀"),
+    (":fire:", "🔥"),
+    (":rocket:", "🚀"),
+    (":check:", "✅"),
+    (":x:", "❌"),
+    (":thinking:", "🤔"),
+    (":pray:", "🙏"),
+];
+// Longest emoji sequence accepted by /react, enough for skin tones and flags
+pub const MAX_REACTION_BYTES: usize = 32;
+
+// Results shown by /search, newest first
+pub const MAX_SEARCH_RESULTS: usize = 20;
+// Characters shown before a search match, about twice as many are shown after it
+pub const SEARCH_EXCERPT_CONTEXT: usize = 30;
+
+// Pinned messages a room can have at once
+pub const MAX_PINS_PER_ROOM: usize = 10;
+
+// Unread messages replayed by /catchup at most, the newest are kept
+pub const MAX_CATCHUP_MESSAGES: usize = 100;
+
+// Clients should stop showing a typing hint after this long, senders repeat /typing before it runs out
+pub const TYPING_EXPIRY_SECS: u64 = 6;
+
+// Members a /gm group can have, the sender included
+pub const MAX_GROUP_MEMBERS: usize = 10;
+
+// Files shared with /upload are stored in this directory, relative to where the server runs
+pub const UPLOAD_DIR: &str = "uploads";
+// Largest file accepted by /upload, after base64 decoding
+pub const MAX_UPLOAD_BYTES: usize = 1024 * 1024;
+// Uploads are refused once the stored files take up this much space
+pub const MAX_UPLOAD_STORAGE_BYTES: usize = 100 * 1024 * 1024;
+pub const MAX_UPLOAD_NAME_LENGTH: usize = 100;
+// File types /upload accepts, by extension
+pub const UPLOAD_EXTENSIONS: &[&str] = &[
+    "txt", "log", "md", "csv", "json", "toml", "yaml", "yml", "rs", "py", "sh", "diff", "patch", "png", "jpg",
+    "jpeg", "gif", "pdf", "zip", "gz",
+];
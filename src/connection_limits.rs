@@ -0,0 +1,121 @@
+use crate::config;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Tracks open connections and recent accepts so the accept loop can turn peers away.
+// This uses a std mutex because slots are released from Drop.
+#[derive(Debug, Default)]
+pub(crate) struct ConnectionTracker {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+    recent_accepts: HashMap<IpAddr, Vec<Instant>>,
+}
+
+// Held by a session task for as long as its connection is open
+#[derive(Debug)]
+pub(crate) struct ConnectionSlot {
+    ip: IpAddr,
+    tracker: Arc<Mutex<ConnectionTracker>>,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut tracker = self.tracker.lock().unwrap();
+        tracker.total -= 1;
+        if let Some(count) = tracker.per_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                tracker.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+// Returns a slot for the connection, or the reason it was rejected
+pub(crate) fn try_admit(
+    tracker: &Arc<Mutex<ConnectionTracker>>,
+    ip: IpAddr,
+) -> Result<ConnectionSlot, &'static str> {
+    let mut guard = tracker.lock().unwrap();
+    let now = Instant::now();
+    let window = Duration::from_secs(config::ACCEPT_WINDOW_SECS);
+
+    // forget accepts that fell out of the window
+    guard.recent_accepts.retain(|_, accepts| {
+        accepts.retain(|accepted_at| now.duration_since(*accepted_at) < window);
+        !accepts.is_empty()
+    });
+
+    let accepts = guard.recent_accepts.entry(ip).or_default();
+    accepts.push(now);
+    if accepts.len() > config::MAX_ACCEPTS_PER_IP {
+        return Err("Too many connection attempts, try again later");
+    }
+
+    if guard.total >= config::MAX_CONNECTIONS {
+        return Err("Server is full, try again later");
+    }
+
+    let count = guard.per_ip.entry(ip).or_insert(0);
+    if *count >= config::MAX_CONNECTIONS_PER_IP {
+        return Err("Too many connections from your address");
+    }
+    *count += 1;
+    guard.total += 1;
+
+    Ok(ConnectionSlot {
+        ip,
+        tracker: tracker.clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn ip(n: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(192, 0, 2, n))
+    }
+
+    #[test]
+    fn limits_connections_per_ip_and_releases_slots() {
+        let tracker = Arc::new(Mutex::new(ConnectionTracker::default()));
+        let mut slots: Vec<ConnectionSlot> = (0..config::MAX_CONNECTIONS_PER_IP)
+            .map(|_| try_admit(&tracker, ip(1)).unwrap())
+            .collect();
+        assert_eq!(try_admit(&tracker, ip(1)).unwrap_err(), "Too many connections from your address");
+        // other addresses are not affected
+        assert!(try_admit(&tracker, ip(2)).is_ok());
+
+        slots.pop();
+        assert!(try_admit(&tracker, ip(1)).is_ok());
+        drop(slots);
+        let guard = tracker.lock().unwrap();
+        assert_eq!(guard.total, 0);
+        assert!(guard.per_ip.is_empty());
+    }
+
+    #[test]
+    fn limits_accepts_per_ip() {
+        let tracker = Arc::new(Mutex::new(ConnectionTracker::default()));
+        // slots are dropped right away, only the attempts count
+        for _ in 0..config::MAX_ACCEPTS_PER_IP {
+            assert!(try_admit(&tracker, ip(1)).is_ok());
+        }
+        assert_eq!(try_admit(&tracker, ip(1)).unwrap_err(), "Too many connection attempts, try again later");
+    }
+
+    #[test]
+    fn limits_total_connections() {
+        let tracker = Arc::new(Mutex::new(ConnectionTracker::default()));
+        let slots: Vec<ConnectionSlot> = (0..config::MAX_CONNECTIONS)
+            .map(|n| try_admit(&tracker, ip((n / config::MAX_CONNECTIONS_PER_IP) as u8)).unwrap())
+            .collect();
+        assert_eq!(try_admit(&tracker, ip(250)).unwrap_err(), "Server is full, try again later");
+        drop(slots);
+        assert!(try_admit(&tracker, ip(250)).is_ok());
+    }
+}
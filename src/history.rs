@@ -0,0 +1,356 @@
+use crate::config;
+use std::time::SystemTime;
+
+// Where a stored message was sent
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Conversation {
+    Room(String),
+    // sender and recipient usernames
+    Private(String, String),
+    // id of a group started with /gm
+    Group(String),
+}
+
+// Ad-hoc private conversation between several users, unlike rooms it is never listed
+#[derive(Debug)]
+pub(crate) struct Group {
+    pub(crate) id: String,
+    // usernames, the group is removed once everyone has left
+    pub(crate) members: Vec<String>,
+}
+
+// One emoji on a message and the usernames that reacted with it
+#[derive(Debug)]
+pub(crate) struct Reaction {
+    pub(crate) emoji: String,
+    pub(crate) users: Vec<String>,
+}
+
+#[derive(Debug)]
+pub(crate) struct StoredMessage {
+    pub(crate) id: String,
+    pub(crate) conversation: Conversation,
+    pub(crate) author: String,
+    // display name of the author when the message was sent
+    pub(crate) author_name: String,
+    pub(crate) text: String,
+    // id of the first message of the thread this message replies to
+    pub(crate) thread: Option<String>,
+    // in the order they were first added
+    pub(crate) reactions: Vec<Reaction>,
+    // usernames notified by @mentions, @room and @here are expanded when the message is sent
+    pub(crate) mentions: Vec<String>,
+    pub(crate) sent_at: SystemTime,
+    pub(crate) edited: bool,
+    // deleted messages are kept as tombstones so their id still resolves
+    pub(crate) deleted: bool,
+}
+
+impl StoredMessage {
+    // single line (or block, for pastes) as it is replayed from history
+    pub(crate) fn render(&self) -> String {
+        if self.deleted {
+            return format!("#{} [message deleted]", self.id);
+        }
+        let edited = if self.edited { " (edited)" } else { "" };
+        let thread = match &self.thread {
+            Some(thread) => format!("(thread #{}) ", thread),
+            None => String::new(),
+        };
+        format!("#{} [{}] {}{}{}{}", self.id, self.author_name, thread, self.text, edited, self.reaction_summary())
+    }
+
+    // e.g. " [👍 2, 🎉 1]", empty when nobody reacted
+    pub(crate) fn reaction_summary(&self) -> String {
+        if self.reactions.is_empty() {
+            return String::new();
+        }
+        let counts: Vec<String> = self
+            .reactions
+            .iter()
+            .map(|r| format!("{} {}", r.emoji, r.users.len()))
+            .collect();
+        format!(" [{}]", counts.join(", "))
+    }
+
+    // returns false if the user already reacted with this emoji
+    pub(crate) fn add_reaction(&mut self, emoji: &str, username: &str) -> bool {
+        match self.reactions.iter_mut().find(|r| r.emoji == emoji) {
+            Some(reaction) if reaction.users.iter().any(|u| u == username) => false,
+            Some(reaction) => {
+                reaction.users.push(username.to_string());
+                true
+            }
+            None => {
+                self.reactions.push(Reaction {
+                    emoji: emoji.to_string(),
+                    users: vec![username.to_string()],
+                });
+                true
+            }
+        }
+    }
+
+    // returns false if the user had not reacted with this emoji
+    pub(crate) fn remove_reaction(&mut self, emoji: &str, username: &str) -> bool {
+        let Some(reaction) = self.reactions.iter_mut().find(|r| r.emoji == emoji) else {
+            return false;
+        };
+        let before = reaction.users.len();
+        reaction.users.retain(|u| u != username);
+        let removed = reaction.users.len() < before;
+        self.reactions.retain(|r| !r.users.is_empty());
+        removed
+    }
+
+    // part of the text around the first search term, on one line
+    pub(crate) fn excerpt(&self, term: Option<&str>) -> String {
+        let text = self.text.replace('\n', " ");
+        // byte offsets only carry over when lowercasing kept the length
+        let lowercase = text.to_lowercase();
+        let found = term
+            .filter(|_| lowercase.len() == text.len())
+            .and_then(|term| lowercase.find(term))
+            .unwrap_or(0);
+        let mut start = found.saturating_sub(config::SEARCH_EXCERPT_CONTEXT);
+        while !text.is_char_boundary(start) {
+            start -= 1;
+        }
+        let mut end = (found + 2 * config::SEARCH_EXCERPT_CONTEXT).min(text.len());
+        while !text.is_char_boundary(end) {
+            end += 1;
+        }
+        let before = if start > 0 { "..." } else { "" };
+        let after = if end < text.len() { "..." } else { "" };
+        format!("{}{}{}", before, &text[start..end], after)
+    }
+
+    // start of the first line, shown when someone replies to the message
+    pub(crate) fn snippet(&self) -> String {
+        if self.deleted {
+            return "[message deleted]".to_string();
+        }
+        let first_line = self.text.lines().next().unwrap_or_default();
+        if first_line.chars().count() > config::REPLY_SNIPPET_CHARS || self.text.contains('\n') {
+            let start: String = first_line.chars().take(config::REPLY_SNIPPET_CHARS).collect();
+            format!("{}...", start)
+        } else {
+            first_line.to_string()
+        }
+    }
+}
+
+// Filters given to /search, every one that is set has to match
+#[derive(Debug, Default)]
+pub(crate) struct SearchQuery {
+    pub(crate) room: Option<String>,
+    // lowercase words that all have to appear in the text
+    pub(crate) terms: Vec<String>,
+    pub(crate) from: Option<String>,
+    pub(crate) after: Option<SystemTime>,
+    pub(crate) before: Option<SystemTime>,
+}
+
+impl SearchQuery {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.terms.is_empty() && self.from.is_none() && self.after.is_none() && self.before.is_none()
+    }
+
+    fn matches(&self, message: &StoredMessage) -> bool {
+        let text = message.text.to_lowercase();
+        !message.deleted
+            && self.room.as_ref().is_none_or(|room| matches!(&message.conversation, Conversation::Room(name) if name == room))
+            && self.terms.iter().all(|term| text.contains(term.as_str()))
+            && self.from.as_ref().is_none_or(|from| {
+                message.author.eq_ignore_ascii_case(from) || message.author_name.eq_ignore_ascii_case(from)
+            })
+            && self.after.is_none_or(|after| message.sent_at >= after)
+            && self.before.is_none_or(|before| message.sent_at < before)
+    }
+}
+
+// Messages kept in memory, oldest first, capped at MAX_HISTORY_MESSAGES
+#[derive(Debug, Default)]
+pub(crate) struct History {
+    messages: Vec<StoredMessage>,
+    next_id: u64,
+    groups: Vec<Group>,
+    next_group_id: u64,
+}
+
+impl History {
+    // store a message and return its id
+    pub(crate) fn record(
+        &mut self,
+        conversation: Conversation,
+        author: &str,
+        author_name: &str,
+        text: &str,
+        thread: Option<String>,
+        mentions: Vec<String>,
+    ) -> String {
+        self.next_id += 1;
+        let id = short_id(self.next_id);
+        self.messages.push(StoredMessage {
+            id: id.clone(),
+            conversation,
+            author: author.to_string(),
+            author_name: author_name.to_string(),
+            text: text.to_string(),
+            thread,
+            reactions: vec![],
+            mentions,
+            sent_at: SystemTime::now(),
+            edited: false,
+            deleted: false,
+        });
+        if self.messages.len() > config::MAX_HISTORY_MESSAGES {
+            self.messages.remove(0);
+        }
+        id
+    }
+
+    pub(crate) fn find(&self, id: &str) -> Option<&StoredMessage> {
+        self.messages.iter().find(|m| m.id == id)
+    }
+
+    pub(crate) fn find_mut(&mut self, id: &str) -> Option<&mut StoredMessage> {
+        self.messages.iter_mut().find(|m| m.id == id)
+    }
+
+    // forget a deleted room's messages, so a new room with the same name starts out empty
+    pub(crate) fn remove_room(&mut self, room_name: &str) {
+        self.messages
+            .retain(|m| !matches!(&m.conversation, Conversation::Room(name) if name == room_name));
+    }
+
+    pub(crate) fn room_messages<'a>(&'a self, room_name: &'a str) -> impl Iterator<Item = &'a StoredMessage> {
+        self.messages
+            .iter()
+            .filter(move |m| matches!(&m.conversation, Conversation::Room(name) if name == room_name))
+    }
+
+    // matching messages the caller may see, newest first
+    pub(crate) fn search<'a>(
+        &'a self,
+        query: &'a SearchQuery,
+        can_see: impl Fn(&StoredMessage) -> bool + 'a,
+    ) -> impl Iterator<Item = &'a StoredMessage> {
+        self.messages
+            .iter()
+            .rev()
+            .filter(move |m| query.matches(m) && can_see(m))
+    }
+
+    // the group with exactly these members keeps its id, returns the id and whether the group is new
+    pub(crate) fn find_or_create_group(&mut self, members: &[String]) -> (String, bool) {
+        let same_members = |group: &&Group| {
+            group.members.len() == members.len() && members.iter().all(|m| group.members.contains(m))
+        };
+        if let Some(group) = self.groups.iter().find(same_members) {
+            return (group.id.clone(), false);
+        }
+        self.next_group_id += 1;
+        let id = format!("g{}", short_id(self.next_group_id));
+        self.groups.push(Group {
+            id: id.clone(),
+            members: members.to_vec(),
+        });
+        (id, true)
+    }
+
+    pub(crate) fn group(&self, id: &str) -> Option<&Group> {
+        self.groups.iter().find(|g| g.id == id)
+    }
+
+    pub(crate) fn group_mut(&mut self, id: &str) -> Option<&mut Group> {
+        self.groups.iter_mut().find(|g| g.id == id)
+    }
+
+    pub(crate) fn remove_empty_groups(&mut self) {
+        self.groups.retain(|g| !g.members.is_empty());
+    }
+
+    pub(crate) fn groups_of<'a>(&'a self, username: &'a str) -> impl Iterator<Item = &'a Group> {
+        self.groups.iter().filter(move |g| g.members.iter().any(|m| m == username))
+    }
+
+    // usernames that see a private or group conversation, rooms are checked against their members instead
+    pub(crate) fn participants(&self, conversation: &Conversation) -> Vec<String> {
+        match conversation {
+            Conversation::Room(_) => vec![],
+            Conversation::Private(sender, recipient) => vec![sender.clone(), recipient.clone()],
+            Conversation::Group(id) => self.group(id).map(|g| g.members.clone()).unwrap_or_default(),
+        }
+    }
+
+    // room messages from others sent after the user's read marker
+    pub(crate) fn unread_in_room<'a>(
+        &'a self,
+        room_name: &'a str,
+        username: &'a str,
+        read_at: SystemTime,
+    ) -> impl Iterator<Item = &'a StoredMessage> {
+        self.room_messages(room_name)
+            .filter(move |m| !m.deleted && m.author != username && m.sent_at > read_at)
+    }
+
+    pub(crate) fn unread_private<'a>(&'a self, username: &'a str, read_at: SystemTime) -> impl Iterator<Item = &'a StoredMessage> {
+        self.messages.iter().filter(move |m| {
+            !m.deleted
+                && m.sent_at > read_at
+                && matches!(&m.conversation, Conversation::Private(_, recipient) if recipient == username)
+        })
+    }
+
+    // messages that mentioned the user, oldest first
+    pub(crate) fn mentions_of<'a>(&'a self, username: &'a str) -> impl Iterator<Item = &'a StoredMessage> {
+        self.messages
+            .iter()
+            .filter(move |m| !m.deleted && m.mentions.iter().any(|u| u == username))
+    }
+
+    // the first message of a thread followed by its replies
+    pub(crate) fn thread_messages<'a>(&'a self, root_id: &'a str) -> impl Iterator<Item = &'a StoredMessage> {
+        self.messages
+            .iter()
+            .filter(move |m| m.id == root_id || m.thread.as_deref() == Some(root_id))
+    }
+}
+
+// ids are a counter in base 36 so they stay short to type, e.g. 1k or 2b9
+pub(crate) fn short_id(mut n: u64) -> String {
+    const DIGITS: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+    let mut id = vec![];
+    while n > 0 {
+        id.push(DIGITS[(n % 36) as usize]);
+        n /= 36;
+    }
+    id.reverse();
+    String::from_utf8(id).unwrap()
+}
+
+// accepts ids typed with or without the leading '#'
+pub(crate) fn parse_id(id: &str) -> &str {
+    id.trim_start_matches('#')
+}
+
+// turns a shortcode like :tada: into its emoji, emoji typed directly are kept as they are
+pub(crate) fn parse_reaction(reaction: &str) -> Option<&str> {
+    if let Some((_, emoji)) = config::EMOJI_SHORTCODES.iter().find(|(code, _)| *code == reaction) {
+        return Some(emoji);
+    }
+    // anything without ascii letters or digits and short enough is taken as an emoji
+    let is_emoji = reaction.len() <= config::MAX_REACTION_BYTES
+        && !reaction.chars().any(|c| c.is_ascii_alphanumeric() || c.is_whitespace());
+    (is_emoji && !reaction.is_ascii()).then_some(reaction)
+}
+
+// names after an '@', e.g. "@bob," gives "bob", "room" and "here" mean everyone in the room
+pub(crate) fn mention_names(text: &str) -> Vec<&str> {
+    text.split_whitespace()
+        .filter_map(|word| word.strip_prefix('@'))
+        .map(|name| name.trim_end_matches(|c: char| c.is_ascii_punctuation() && c != '_' && c != '-'))
+        .filter(|name| !name.is_empty())
+        .collect()
+}
@@ -0,0 +1,45 @@
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+// Smoothed round trip time the kernel keeps for a connection, so latency is measured
+// without the client having to answer anything. None where TCP_INFO is not available.
+#[cfg(target_os = "linux")]
+pub(crate) fn round_trip_time(socket: &TcpStream) -> Option<Duration> {
+    use std::os::fd::AsRawFd;
+
+    // start of struct tcp_info from linux/tcp.h, the kernel only fills in as much as fits
+    #[repr(C)]
+    #[derive(Default)]
+    struct TcpInfo {
+        // state, options and window scales
+        flags: [u8; 8],
+        // rto, ato, mss, packet counters, last send and receive times, pmtu and rcv_ssthresh
+        before_rtt: [u32; 15],
+        // in microseconds
+        rtt: u32,
+    }
+
+    let mut info = TcpInfo::default();
+    let mut len = std::mem::size_of::<TcpInfo>() as libc::socklen_t;
+    // SAFETY: info is a plain C struct and len keeps the kernel from writing past its end
+    let result = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut TcpInfo as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    (result == 0 && len as usize == std::mem::size_of::<TcpInfo>()).then(|| Duration::from_micros(info.rtt.into()))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn round_trip_time(_socket: &TcpStream) -> Option<Duration> {
+    None
+}
+
+// e.g. 0.04 ms or 38.00 ms, loopback and LAN connections are well below a millisecond
+pub(crate) fn format_latency(latency: Duration) -> String {
+    format!("{:.2} ms", latency.as_secs_f64() * 1000.0)
+}
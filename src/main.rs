@@ -1,30 +1,406 @@
 use local_ip_address::local_ip;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use socket2::{SockRef, TcpKeepalive};
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
     net::TcpListener,
-    sync::{broadcast, Mutex as TokioMutex},
+    sync::{broadcast, broadcast::error::RecvError, Mutex as TokioMutex},
 };
 mod client_commands;
 mod color_codes;
+mod config;
+mod connection_limits;
+mod history;
+mod latency;
+mod rate_limit;
+mod uploads;
+use crate::connection_limits::ConnectionTracker;
+use crate::history::History;
+use crate::rate_limit::{RateLimiter, Verdict};
+use crate::uploads::{Upload, Uploads};
 use crate::client_commands::{
-    handle_create_room_command, handle_help_command, handle_join_room_command,
-    handle_leave_room_command, handle_list_command, handle_m_room_command, handle_pm_command,
-    handle_report_command, handle_view_users_command,
+    admit_from_waitlist, close_room, handle_accept_command, handle_away_command,
+    handle_back_command, handle_capacity_command, handle_catchup_command,
+    handle_create_room_command, handle_decline_command, handle_delete_command,
+    handle_describe_command, handle_download_command, handle_edit_command, handle_finished_upload,
+    handle_gm_add_command, handle_gm_command, handle_gm_leave_command, handle_groups_command,
+    handle_help_command, handle_history_command, handle_invite_command, handle_invites_command,
+    handle_join_room_command, handle_leave_room_command, handle_list_command, handle_m_room_command,
+    handle_mentions_command, handle_nick_command, handle_pin_command, handle_pins_command,
+    handle_pm_command, handle_profile_command, handle_react_command, handle_read_only_command,
+    handle_reply_command, handle_report_command, handle_room_info_command, handle_search_command,
+    handle_switch_command, handle_thread_command, handle_topic_command, handle_typing_command,
+    handle_unpin_command, handle_unreact_command, handle_unread_command, handle_upload_command,
+    handle_view_rooms_command, handle_view_users_command, handle_visibility_command,
+    handle_waitlist_command, handle_whois_command, join_global_room, send_room_message,
+    unread_summary,
 };
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Role {
+    User,
+    Admin,
+}
+
 // Define a struct to store user information including their username
 #[derive(Debug)]
 struct UserInfo {
     username: String,
+    // display name set with /nick, commands still address the user by username
+    nick: Option<String>,
     addr: std::net::SocketAddr,
     rooms: Vec<String>,
+    role: Role,
+    connected_at: Instant,
+    last_active: Instant,
+    away: Option<Away>,
+    // round trip time of the connection as measured by the kernel, refreshed by /ping and the idle check
+    latency: Option<Duration>,
+    // private messages sent after this are unread
+    pms_read_at: SystemTime,
+}
+
+#[derive(Debug)]
+struct Away {
+    message: String,
+    since: Instant,
+    // set when the user was marked away for being idle rather than with /away
+    automatic: bool,
+}
+
+impl UserInfo {
+    fn new(username: &str, addr: std::net::SocketAddr) -> Self {
+        let role = if config::ADMIN_IPS.contains(&addr.ip().to_string().as_str()) {
+            Role::Admin
+        } else {
+            Role::User
+        };
+
+        UserInfo {
+            username: username.to_string(),
+            nick: None,
+            addr,
+            rooms: vec![],
+            role,
+            connected_at: Instant::now(),
+            last_active: Instant::now(),
+            away: None,
+            latency: None,
+            pms_read_at: SystemTime::now(),
+        }
+    }
+
+    // name shown next to the user's messages
+    fn display_name(&self) -> &str {
+        self.nick.as_deref().unwrap_or(&self.username)
+    }
+
+    // name used in user listings, shows the username too when a nick is set
+    fn listing_name(&self) -> String {
+        match &self.nick {
+            Some(nick) => format!("[{}] ({})", nick, self.username),
+            None => format!("[{}]", self.username),
+        }
+    }
+
+    // online, idle or away, with how long the user has been in that state
+    fn presence(&self) -> String {
+        if let Some(away) = &self.away {
+            let kind = if away.automatic { "away (idle)" } else { "away" };
+            return format!("{} {}: {}", kind, format_duration(away.since.elapsed()), away.message);
+        }
+
+        let idle = self.last_active.elapsed();
+        if idle >= Duration::from_secs(config::SHOW_IDLE_AFTER_SECS) {
+            format!("idle {}", format_duration(idle))
+        } else {
+            "online".to_string()
+        }
+    }
+}
+
+// short human readable duration, e.g. 45s, 12m or 3h 5m
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs < 60 {
+        format!("{}s", secs)
+    } else if secs < 60 * 60 {
+        format!("{}m", secs / 60)
+    } else {
+        format!("{}h {}m", secs / 3600, (secs % 3600) / 60)
+    }
+}
+
+// UTC timestamp like 2024-04-12 09:30 UTC, there is no date crate in the dependencies
+fn format_timestamp(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let days = (secs / 86400) as i64;
+    let secs_of_day = secs % 86400;
+
+    // convert days since the epoch to a civil date (Howard Hinnant's algorithm)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        (secs_of_day % 3600) / 60
+    )
+}
+
+// start of a day given as YYYY-MM-DD in UTC, the inverse of format_timestamp's date part
+fn parse_date(date: &str) -> Option<SystemTime> {
+    let mut parts = date.splitn(3, '-');
+    let year: i64 = parts.next()?.parse().ok()?;
+    let month: i64 = parts.next()?.parse().ok()?;
+    let day: i64 = parts.next()?.parse().ok()?;
+    // keeps the arithmetic below and adding a day to the result from overflowing
+    if !(1970..=9999).contains(&year) {
+        return None;
+    }
+    let days_in_month = match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    if !(1..=12).contains(&month) || !(1..=days_in_month).contains(&day) {
+        return None;
+    }
+
+    // days since the epoch from a civil date (Howard Hinnant's algorithm)
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    let secs = u64::try_from(days * 86400).ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+// Profile details are kept per username so they survive reconnects
+#[derive(Debug)]
+struct Profile {
+    username: String,
+    bio: Option<String>,
+    pronouns: Option<String>,
+    timezone: Option<String>,
+    status: Option<String>,
+    // first time this username connected to the server
+    registered_at: SystemTime,
+}
+
+impl Profile {
+    fn new(username: &str) -> Self {
+        Profile {
+            username: username.to_string(),
+            bio: None,
+            pronouns: None,
+            timezone: None,
+            status: None,
+            registered_at: SystemTime::now(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Visibility {
+    // listed in /view_rooms and open to everyone
+    Public,
+    // left out of /view_rooms, but anyone who knows the name can join
+    Unlisted,
+    // left out of /view_rooms and only joinable with an invite
+    InviteOnly,
+}
+
+impl Visibility {
+    fn parse(mode: &str) -> Option<Self> {
+        match mode {
+            "public" => Some(Visibility::Public),
+            "unlisted" => Some(Visibility::Unlisted),
+            "invite_only" => Some(Visibility::InviteOnly),
+            _ => None,
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            Visibility::Public => "public",
+            Visibility::Unlisted => "unlisted",
+            Visibility::InviteOnly => "invite only",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Lifecycle {
+    // kept until the server stops
+    Persistent,
+    // deleted as soon as the last member leaves
+    Ephemeral,
+    // deleted after this long without activity
+    Ttl(Duration),
+}
+
+impl Lifecycle {
+    // accepts persistent, ephemeral or ttl:<minutes>
+    fn parse(option: &str) -> Option<Self> {
+        match option {
+            "persistent" => Some(Lifecycle::Persistent),
+            "ephemeral" => Some(Lifecycle::Ephemeral),
+            _ => {
+                let minutes: u64 = option.strip_prefix("ttl:")?.parse().ok()?;
+                (minutes > 0).then(|| Lifecycle::Ttl(Duration::from_secs(minutes * 60)))
+            }
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Lifecycle::Persistent => "persistent".to_string(),
+            Lifecycle::Ephemeral => "ephemeral, deleted when the last member leaves".to_string(),
+            Lifecycle::Ttl(ttl) => format!("deleted after {} without activity", format_duration(*ttl)),
+        }
+    }
 }
 
 #[derive(Debug)]
 struct Room {
     name: String,
     users: Vec<UserInfo>,
+    // usernames kept as members while offline, see config::KEEP_ROOMS_ON_DISCONNECT
+    offline_members: Vec<String>,
+    topic: Option<String>,
+    description: Option<String>,
+    creator: String,
+    created_at: SystemTime,
+    // usernames allowed to change the room's settings, starts out as just the creator
+    operators: Vec<String>,
+    // last time someone joined or sent a message, used to sort /view_rooms
+    last_activity: Instant,
+    visibility: Visibility,
+    // usernames with a pending invite to the room
+    invited: Vec<String>,
+    max_members: Option<usize>,
+    // usernames waiting for a spot in a full room, first in first out
+    waitlist: Vec<String>,
+    lifecycle: Lifecycle,
+    // only operators and admins may post, e.g. for announcements
+    read_only: bool,
+    // ids of pinned messages, oldest pin first
+    pinned: Vec<String>,
+    // per username, messages sent after this are unread, kept across reconnects
+    read_markers: Vec<(String, SystemTime)>,
+}
+
+impl Room {
+    fn new(name: &str, creator: &str) -> Self {
+        Room {
+            name: name.to_string(),
+            users: vec![],
+            offline_members: vec![],
+            topic: None,
+            description: None,
+            creator: creator.to_string(),
+            created_at: SystemTime::now(),
+            operators: vec![creator.to_string()],
+            last_activity: Instant::now(),
+            visibility: Visibility::Public,
+            invited: vec![],
+            max_members: None,
+            waitlist: vec![],
+            lifecycle: Lifecycle::Ttl(Duration::from_secs(config::DEFAULT_ROOM_TTL_SECS)),
+            read_only: false,
+            pinned: vec![],
+            read_markers: vec![],
+        }
+    }
+
+    // ephemeral rooms go away once nobody, online or kept offline, is left in them
+    fn is_abandoned(&self) -> bool {
+        self.lifecycle == Lifecycle::Ephemeral
+            && self.users.is_empty()
+            && self.offline_members.is_empty()
+    }
+
+    fn is_expired(&self) -> bool {
+        match self.lifecycle {
+            Lifecycle::Persistent => false,
+            // also clean up ephemeral rooms that nobody ever joined
+            Lifecycle::Ephemeral => {
+                self.is_abandoned()
+                    && self.last_activity.elapsed() >= Duration::from_secs(config::EPHEMERAL_ROOM_GRACE_SECS)
+            }
+            Lifecycle::Ttl(ttl) => self.last_activity.elapsed() >= ttl,
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.max_members.is_some_and(|max| self.users.len() >= max)
+    }
+
+    fn is_member(&self, username: &str) -> bool {
+        self.users.iter().any(|u| u.username == username)
+    }
+
+    // non-members of unlisted and invite only rooms are treated as if the room didn't exist
+    fn is_hidden_from(&self, username: &str) -> bool {
+        self.visibility != Visibility::Public
+            && !self.is_member(username)
+            && !self.is_operator(username)
+    }
+
+    fn is_joinable_by(&self, username: &str) -> bool {
+        self.visibility != Visibility::InviteOnly
+            || self.is_operator(username)
+            || self.invited.iter().any(|u| u == username)
+    }
+
+    fn is_operator(&self, username: &str) -> bool {
+        self.operators.iter().any(|u| u == username)
+    }
+
+    fn read_marker(&self, username: &str) -> Option<SystemTime> {
+        self.read_markers.iter().find(|(u, _)| u == username).map(|(_, read_at)| *read_at)
+    }
+
+    // markers only move forward, messages can be delivered out of order with catching up
+    fn mark_read(&mut self, username: &str, read_at: SystemTime) {
+        match self.read_markers.iter_mut().find(|(u, _)| u == username) {
+            Some((_, marker)) => *marker = (*marker).max(read_at),
+            None => self.read_markers.push((username.to_string(), read_at)),
+        }
+    }
+
+    // shown to users when they join the room
+    fn banner(&self) -> String {
+        let mut banner = String::new();
+        if let Some(topic) = &self.topic {
+            banner.push_str(&format!("Topic: {}\n", topic));
+        }
+        if let Some(description) = &self.description {
+            banner.push_str(&format!("Description: {}\n", description));
+        }
+        banner.push_str(&format!(
+            "Created by {} on {}\n",
+            self.creator,
+            format_timestamp(self.created_at)
+        ));
+        banner
+    }
 }
 
 #[tokio::main]
@@ -44,57 +420,369 @@ async fn main() {
         }
     };
 
-    let (tx, _rx) = broadcast::channel(10);
+    let (tx, _rx) = broadcast::channel(config::BROADCAST_CAPACITY);
 
     // let users = Arc::new(Mutex::new(vec![]));
     let users = Arc::new(TokioMutex::new(vec![]));
 
-    let rooms = Arc::new(TokioMutex::new(vec![]));
+    // the global room has no creator or operators, admins moderate it
+    let mut global_room = Room::new(config::GLOBAL_ROOM, "server");
+    global_room.operators.clear();
+    global_room.lifecycle = Lifecycle::Persistent;
+    let rooms = Arc::new(TokioMutex::new(vec![global_room]));
+
+    let profiles = Arc::new(TokioMutex::new(vec![]));
+
+    let history = Arc::new(TokioMutex::new(History::default()));
+
+    let uploads = Arc::new(TokioMutex::new(Uploads::default()));
+
+    let connections = Arc::new(Mutex::new(ConnectionTracker::default()));
+
+    // delete rooms whose lifecycle has run out
+    let sweeper_tx = tx.clone();
+    let sweeper_users = users.clone();
+    let sweeper_rooms = rooms.clone();
+    let sweeper_history = history.clone();
+    let sweeper_uploads = uploads.clone();
+    tokio::spawn(async move {
+        let mut sweep = tokio::time::interval(Duration::from_secs(config::ROOM_SWEEP_SECS));
+        loop {
+            sweep.tick().await;
+            sweep_expired_rooms(sweeper_tx.clone(), sweeper_users.clone(), sweeper_rooms.clone(), sweeper_history.clone(), sweeper_uploads.clone()).await;
+        }
+    });
 
     loop {
-        let (mut socket, addr) = listener.accept().await.unwrap();
+        let (mut socket, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                println!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
         println!("New connection from: {}", addr);
 
+        let keepalive = TcpKeepalive::new()
+            .with_time(Duration::from_secs(config::KEEPALIVE_TIME_SECS))
+            .with_interval(Duration::from_secs(config::KEEPALIVE_INTERVAL_SECS));
+        if let Err(e) = SockRef::from(&socket).set_tcp_keepalive(&keepalive) {
+            println!("Failed to enable keepalive for {}: {}", addr, e);
+        }
+
+        let connection_slot = match connection_limits::try_admit(&connections, addr.ip()) {
+            Ok(slot) => slot,
+            Err(reason) => {
+                println!("Rejected connection from {}: {}", addr, reason);
+                // write the reason off the accept loop so a slow peer can't stall it
+                tokio::spawn(async move {
+                    let _ = socket.write_all(format!("[i] {}\n", reason).as_bytes()).await;
+                    let _ = socket.shutdown().await;
+                });
+                continue;
+            }
+        };
+
         let tx = tx.clone();
         let users = users.clone();
         let rooms = rooms.clone();
+        let profiles = profiles.clone();
+        let history = history.clone();
+        let uploads = uploads.clone();
         let mut rx = tx.subscribe();
 
         tokio::spawn(async move {
-            // Ask for username
-            let username = ask_for_username(&mut socket).await.unwrap();
-            println!("User {} connected from: {}", username, addr);
+            // released when this task ends, however the session finishes
+            let _connection_slot = connection_slot;
+
+            // Ask for username until a free one is given, the timeout covers every attempt
+            let login_deadline = tokio::time::Instant::now() + Duration::from_secs(config::LOGIN_TIMEOUT_SECS);
+            let (username, role) = loop {
+                let username = match tokio::time::timeout_at(login_deadline, ask_for_username(&mut socket)).await {
+                    Ok(Ok(username)) => username,
+                    Ok(Err(e)) => {
+                        println!("Failed to read username from {}: {}", addr, e);
+                        return;
+                    }
+                    Err(_) => {
+                        println!("Login timed out for {}", addr);
+                        let _ = socket.write_all(b"\n[i] Login timed out\n").await;
+                        return;
+                    }
+                };
 
-            // Store user information
-            let user_info = UserInfo {
-                username: username.clone(),
-                addr,
-                rooms: vec![],
+                if !is_valid_username(&username) {
+                    let reply = format!("{}[i] Usernames must be 1 to {} characters without spaces or brackets{}\n", color_codes::RED, config::MAX_USERNAME_LENGTH, color_codes::RESET);
+                    if socket.write_all(reply.as_bytes()).await.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+
+                // checked and added under one lock so two logins can't take the same name
+                let mut users_guard = users.lock().await;
+                if username_taken(&username, &users_guard) {
+                    drop(users_guard);
+                    let reply = format!("{}[i] Username {} is already in use{}\n", color_codes::RED, username, color_codes::RESET);
+                    if socket.write_all(reply.as_bytes()).await.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+                // Store user information
+                let user_info = UserInfo::new(&username, addr);
+                let role = user_info.role;
+                users_guard.push(user_info);
+                drop(users_guard);
+                break (username, role);
             };
+            println!("User {} connected from: {}", username, addr);
+
+            let mut limiter = RateLimiter::new(match role {
+                Role::Admin => &config::ADMIN_RATE_LIMITS,
+                Role::User => &config::USER_RATE_LIMITS,
+            });
 
-            // Add user to the list of users
-            let mut users_guard = users.lock().await;
-            users_guard.push(user_info);
-            drop(users_guard);
+            // the first login under a username registers its profile
+            let mut profiles_guard = profiles.lock().await;
+            if !profiles_guard.iter().any(|p: &Profile| p.username == username) {
+                profiles_guard.push(Profile::new(&username));
+            }
+            drop(profiles_guard);
+
+            let mut greeting = restore_room_memberships(&username, addr, rooms.clone(), users.clone()).await.concat();
+            if let Some(banner) = join_global_room(&username, addr, rooms.clone(), users.clone()).await {
+                greeting.push_str(&banner);
+            }
+            // tell returning users what they missed while they were gone
+            if let Some(summary) = unread_summary(&username, rooms.clone(), users.clone(), history.clone()).await {
+                greeting.push_str(&summary);
+            }
+            if socket.write_all(greeting.as_bytes()).await.is_err() {
+                handle_user_disconnection(&username, &addr, tx.clone(), users.clone(), rooms.clone(), history.clone(), uploads.clone()).await;
+                return;
+            }
 
             let (read_half, mut write_half) = socket.split();
 
-            let mut reader = BufReader::new(read_half);
+            // the take() limit is reset every iteration so a single line can never grow past MAX_LINE_LENGTH
+            let mut reader = BufReader::new(read_half).take(0);
+            // raw bytes of the line being read, they are only decoded once the length has been checked
+            // so a multi-byte character cut off by the limit can't turn an over-long line into a decoding error
+            let mut buffer: Vec<u8> = vec![];
             let mut line = String::new();
+            // set while the rest of an over-long line is being thrown away
+            let mut discarding_line = false;
+
+            let mut last_active = Instant::now();
+            let mut idle_check = tokio::time::interval(Duration::from_secs(config::IDLE_CHECK_SECS));
+
+            // room plain lines are sent to, changed with /switch
+            let mut active_room = config::GLOBAL_ROOM.to_string();
+            let mut show_prompt = false;
+            // set by clients that want [event] lines, plain telnet sessions never see them
+            let mut machine_mode = false;
+            // replies to other people's threads stay out of the room stream when off
+            let mut show_thread_replies = true;
+            // set while a multi-line message is being collected
+            let mut paste: Option<Paste> = None;
+            // set while the base64 lines of an /upload are being collected
+            let mut upload: Option<Upload> = None;
 
             loop {
+                reader.set_limit((config::MAX_LINE_LENGTH + 1 - buffer.len()) as u64);
+
                 tokio::select! {
-                    result = reader.read_line(&mut line) => {
-                        if let Ok(0) = result {
-                            handle_user_disconnection(&username, &addr, tx.clone(), users.clone()).await;
+                    result = reader.read_until(b'\n', &mut buffer) => {
+                        // a read error means the peer is gone just like EOF does
+                        if matches!(result, Ok(0) | Err(_)) {
+                            break;
+                        }
+
+                        if discarding_line {
+                            discarding_line = !buffer.ends_with(b"\n");
+                            buffer.clear();
+                            continue;
+                        }
 
+                        if buffer.len() > config::MAX_LINE_LENGTH {
+                            discarding_line = !buffer.ends_with(b"\n");
+                            buffer.clear();
+                            if write_half.write_all(format!("{}[i] Line too long (max {} bytes), message dropped{}\n", color_codes::RED, config::MAX_LINE_LENGTH, color_codes::RESET).as_bytes()).await.is_err() {
+                                break;
+                            }
+                            continue;
+                        }
+
+                        // a line that isn't valid UTF-8 is dropped, the session carries on
+                        let decoded = std::str::from_utf8(&buffer).map(|text| line.push_str(text));
+                        buffer.clear();
+                        if decoded.is_err() {
+                            if write_half.write_all(format!("{}[i] Line is not valid UTF-8, message dropped{}\n", color_codes::RED, color_codes::RESET).as_bytes()).await.is_err() {
+                                break;
+                            }
+                            continue;
+                        }
+
+                        // lines of a multi-line message are collected until the block is closed,
+                        // the finished block then goes through the rate limiter as one message
+                        // lines taken by an open paste or upload are charged to their own bucket as they arrive,
+                        // this is the verdict for such a line
+                        let mut collected_verdict = None;
+                        let mut finished_paste = None;
+                        if let Some(open_paste) = paste.as_mut() {
+                            let content = line.trim_end_matches(['\r', '\n']);
+                            let closing = if open_paste.fenced { "```" } else { "/end" };
+                            if content.trim() == "/cancel" {
+                                paste = None;
+                                if write_half.write_all(b"[i] Multi-line message cancelled\n").await.is_err() {
+                                    break;
+                                }
+                                line.clear();
+                                continue;
+                            }
+                            if content.trim() != closing {
+                                let verdict = limiter.check_paste_line();
+                                if open_paste.dropped {
+                                    // the rest of a dropped message is thrown away up to its closing line
+                                } else if verdict != Verdict::Allow {
+                                    open_paste.dropped = true;
+                                    if write_half.write_all(format!("{}[i] You are sending lines too fast, message dropped up to the closing {}{}\n", color_codes::RED, closing, color_codes::RESET).as_bytes()).await.is_err() {
+                                        break;
+                                    }
+                                } else if open_paste.lines.len() >= config::MAX_PASTE_LINES || open_paste.bytes + content.len() > config::MAX_PASTE_BYTES {
+                                    open_paste.dropped = true;
+                                    if write_half.write_all(format!("{}[i] Multi-line message too long (max {} lines, {} bytes), message dropped up to the closing {}{}\n", color_codes::RED, config::MAX_PASTE_LINES, config::MAX_PASTE_BYTES, closing, color_codes::RESET).as_bytes()).await.is_err() {
+                                        break;
+                                    }
+                                } else {
+                                    open_paste.bytes += content.len();
+                                    open_paste.lines.push(content.to_string());
+                                }
+                                collected_verdict = Some(verdict);
+                            } else {
+                                let finished = paste.take();
+                                if finished.as_ref().is_some_and(|finished| finished.dropped) {
+                                    line.clear();
+                                    continue;
+                                }
+                                finished_paste = finished;
+                            }
+                        }
+
+                        // uploads are collected the same way, the finished file is charged like one message
+                        let mut finished_upload = None;
+                        if let Some(open_upload) = upload.as_mut() {
+                            let content = line.trim();
+                            if content == "/cancel" {
+                                upload = None;
+                                if write_half.write_all(b"[i] Upload cancelled\n").await.is_err() {
+                                    break;
+                                }
+                                line.clear();
+                                continue;
+                            }
+                            if content == "/end" {
+                                finished_upload = upload.take().filter(|finished| !finished.dropped);
+                                if finished_upload.is_none() {
+                                    if write_half.write_all(b"[i] Upload finished, nothing was shared\n").await.is_err() {
+                                        break;
+                                    }
+                                    line.clear();
+                                    continue;
+                                }
+                            } else {
+                                let verdict = limiter.check_upload(line.len());
+                                if open_upload.dropped {
+                                    // the rest of a dropped upload is thrown away up to /end or /cancel
+                                } else if verdict != Verdict::Allow {
+                                    open_upload.dropped = true;
+                                    if write_half.write_all(format!("{}[i] You are uploading too fast, upload dropped, send /end or /cancel to stop it{}\n", color_codes::RED, color_codes::RESET).as_bytes()).await.is_err() {
+                                        break;
+                                    }
+                                } else if open_upload.encoded.len() + content.len() > config::MAX_UPLOAD_BYTES.div_ceil(3) * 4 {
+                                    open_upload.dropped = true;
+                                    if write_half.write_all(format!("{}[i] File too large (max {} bytes), upload dropped, send /end or /cancel to stop it{}\n", color_codes::RED, config::MAX_UPLOAD_BYTES, color_codes::RESET).as_bytes()).await.is_err() {
+                                        break;
+                                    }
+                                } else {
+                                    open_upload.encoded.push_str(content);
+                                }
+                                collected_verdict = Some(verdict);
+                            }
+                        }
+
+                        let is_collected_line = collected_verdict.is_some();
+                        let (is_command, len) = match (&finished_paste, &finished_upload) {
+                            (Some(finished), _) => (false, finished.bytes),
+                            (_, Some(finished)) => (false, finished.name.len()),
+                            _ => (line.starts_with('/'), line.len()),
+                        };
+                        let verdict = match collected_verdict {
+                            Some(verdict) => verdict,
+                            None if line.trim() == "/exit" => Verdict::Allow,
+                            None => limiter.check(is_command, len),
+                        };
+                        match verdict {
+                            Verdict::Allow => {},
+                            Verdict::Warn => {
+                                if write_half.write_all(format!("{}[i] You are sending messages too fast, slow down{}\n", color_codes::YELLOW, color_codes::RESET).as_bytes()).await.is_err() {
+                                    break;
+                                }
+                                line.clear();
+                                continue;
+                            },
+                            Verdict::Throttle => {
+                                line.clear();
+                                continue;
+                            },
+                            Verdict::Mute(duration) => {
+                                println!("User {} muted for flooding", username);
+                                if write_half.write_all(format!("{}[i] You have been muted for {} seconds for flooding{}\n", color_codes::RED, duration.as_secs(), color_codes::RESET).as_bytes()).await.is_err() {
+                                    break;
+                                }
+                                line.clear();
+                                continue;
+                            },
+                            Verdict::Disconnect => {
+                                println!("User {} disconnected for flooding", username);
+                                if write_half.write_all(format!("{}[i] You have been disconnected for flooding{}\n", color_codes::RED, color_codes::RESET).as_bytes()).await.is_err() {
+                                    break;
+                                }
+                                break;
+                            },
+                        }
+
+                        last_active = Instant::now();
+                        if mark_user_active(&username, users.clone()).await
+                            && write_half.write_all(format!("{}[i] You are no longer marked as away{}\n", color_codes::GREEN, color_codes::RESET).as_bytes()).await.is_err()
+                        {
                             break;
                         }
 
+                        if is_collected_line {
+                            line.clear();
+                            continue;
+                        }
+
+                        if let Some(finished) = finished_paste {
+                            if !finished.lines.is_empty() {
+                                println!("Broadcasting {} line message from {} to {}", finished.lines.len(), username, finished.room);
+                                send_room_message(&mut write_half, &finished.room, &finished.render(), None, &username, addr, tx.clone(), rooms.clone(), users.clone(), profiles.clone(), history.clone()).await;
+                            }
+                            line.clear();
+                            continue;
+                        }
+
+                        if let Some(finished) = finished_upload {
+                            handle_finished_upload(&mut write_half, finished, &username, addr, tx.clone(), rooms.clone(), users.clone(), profiles.clone(), history.clone(), uploads.clone()).await;
+                            line.clear();
+                            continue;
+                        }
 
                         if line.starts_with('/') {
-                            let words: Vec<&str> = line.trim().split_whitespace().collect();
-                            let command = words.get(0).unwrap_or(&"");
+                            let words: Vec<&str> = line.split_whitespace().collect();
+                            let command = words.first().unwrap_or(&"");
 
                             match *command {
                                 "/help" => {
@@ -104,6 +792,22 @@ async fn main() {
                                         let help_text = format!("\n{}use /help <command> to get details on a specific command{}\n
 {}/list        - List all connected users
 /pm          - Send a private message to any connected user
+/gm          - Send a message to a group of users
+/gm_add      - Add a user to a group
+/gm_leave    - Leave a group
+/groups      - List your groups
+/edit        - Change the text of a message you sent
+/delete      - Delete a message
+/history     - Show the last messages sent to a room
+/mentions    - Show the last messages that mentioned you
+/search      - Search messages in your rooms and private messages
+/unread      - Show unread message counts per room
+/catchup     - Show the unread messages of a room
+/react       - React to a message with an emoji
+/unreact     - Remove your reaction from a message
+/reply       - Reply to a room message
+/thread      - Show a message and its replies
+/thread_replies - Show or hide replies in your room messages
 /report      - Report a user to the server admin
 /exit        - Disconnect from the server
 /create_room - Create a new chat room
@@ -111,9 +815,39 @@ async fn main() {
 /leave_room  - Leave a chat room
 /view_rooms  - View all chat rooms
 /view_users  - View users in a specific chat room
-/m_room      - Send a message to all users in a specific room{}\n\n", color_codes::GREEN, color_codes::RESET, color_codes::YELLOW, color_codes::RESET);
+/m_room      - Send a message to all users in a specific room
+/say         - Same as /m_room
+/switch      - Choose the room your plain messages go to
+/paste       - Send a multi-line message
+/upload      - Share a file with a room or user
+/download    - Get a shared file
+/prompt      - Show the room your plain messages go to before each line
+/topic       - Set the topic of a room you operate
+/describe    - Set the description of a room you operate
+/visibility  - Make a room you operate public, unlisted or invite only
+/room_info   - Show details about a room
+/capacity    - Limit how many members a room you operate can have
+/waitlist    - Wait for a spot in a full room
+/read_only   - Only let operators post in a room
+/pin         - Pin a message in a room you operate
+/unpin       - Unpin a message
+/pins        - List the pinned messages of a room
+/invite      - Invite a user to a room
+/invites     - List your pending room invites
+/accept      - Accept a room invite
+/decline     - Decline a room invite
+/away        - Mark yourself as away
+/back        - Clear your away status
+/nick        - Set the name shown next to your messages
+/profile     - View or edit your profile
+/whois       - Show information about a user
+/ping        - Measure your latency to the server
+/machine     - Receive events meant for programs, e.g. typing hints
+/typing      - Tell a room or user that you are typing{}\n\n", color_codes::GREEN, color_codes::RESET, color_codes::YELLOW, color_codes::RESET);
 
-                                            write_half.write_all(help_text.as_bytes()).await.unwrap();
+                                            if write_half.write_all(help_text.as_bytes()).await.is_err() {
+                                                break;
+                                            }
                                     } else {
                                         handle_help_command(&mut write_half, &line).await;
                                     }
@@ -124,25 +858,101 @@ async fn main() {
                                     handle_create_room_command(&mut write_half, &line, &username, rooms.clone()).await;
                                 },
                                 "/join_room" => {
-                                    handle_join_room_command(&mut write_half, &line, &username, addr, rooms.clone(), users.clone()).await;
+                                    handle_join_room_command(&mut write_half, &line, &username, addr, rooms.clone(), users.clone(), history.clone()).await;
                                 },
                                 "/leave_room" => {
-                                    handle_leave_room_command(&mut write_half, &line, &username, rooms.clone(), users.clone()).await;
+                                    handle_leave_room_command(&mut write_half, &line, &username, tx.clone(), rooms.clone(), users.clone(), history.clone(), uploads.clone()).await;
+                                    if words.get(1) == Some(&active_room.as_str()) && active_room != config::GLOBAL_ROOM {
+                                        active_room = config::GLOBAL_ROOM.to_string();
+                                        if write_half.write_all(format!("[i] Plain messages now go to {}\n", active_room).as_bytes()).await.is_err() {
+                                            break;
+                                        }
+                                    }
+                                },
+                                "/switch" | "/focus" => {
+                                    if let Some(room_name) = handle_switch_command(&mut write_half, &line, &username, &active_room, rooms.clone()).await {
+                                        active_room = room_name;
+                                    }
+                                },
+                                "/paste" => {
+                                    let room_name = words.get(1).map_or(active_room.as_str(), |room_name| room_name);
+                                    paste = Some(Paste::new(room_name, false, None));
+                                    if write_half.write_all(format!("[i] Type your message for {}, finish with /end or cancel with /cancel\n", room_name).as_bytes()).await.is_err() {
+                                        break;
+                                    }
+                                },
+                                "/upload" => {
+                                    upload = handle_upload_command(&mut write_half, &line, &username, &active_room, rooms.clone(), users.clone()).await;
+                                },
+                                "/download" => {
+                                    handle_download_command(&mut write_half, &line, &username, rooms.clone(), uploads.clone()).await;
+                                },
+                                "/prompt" => {
+                                    match words.get(1) {
+                                        Some(&"on") => show_prompt = true,
+                                        Some(&"off") => show_prompt = false,
+                                        _ => {
+                                            if write_half.write_all(format!("\n{}[i] Usage: /prompt <on|off>{}\n\n", color_codes::YELLOW, color_codes::RESET).as_bytes()).await.is_err() {
+                                                break;
+                                            }
+                                        }
+                                    }
+                                },
+                                "/machine" => {
+                                    match words.get(1) {
+                                        Some(&"on") => machine_mode = true,
+                                        Some(&"off") => machine_mode = false,
+                                        _ => {
+                                            if write_half.write_all(format!("\n{}[i] Usage: /machine <on|off>{}\n\n", color_codes::YELLOW, color_codes::RESET).as_bytes()).await.is_err() {
+                                                break;
+                                            }
+                                        }
+                                    }
+                                },
+                                "/typing" => {
+                                    handle_typing_command(&mut write_half, &line, &username, addr, tx.clone(), rooms.clone(), users.clone()).await;
+                                },
+                                "/m_room" | "/say" => {
+                                    handle_m_room_command(&mut write_half, &line, &username, addr, tx.clone(), rooms.clone(), users.clone(), profiles.clone(), history.clone()).await;
+                                },
+                                "/topic" => {
+                                    handle_topic_command(&mut write_half, &line, &username, addr, tx.clone(), rooms.clone(), users.clone()).await;
+                                },
+                                "/describe" => {
+                                    handle_describe_command(&mut write_half, &line, &username, rooms.clone(), users.clone()).await;
+                                },
+                                "/visibility" => {
+                                    handle_visibility_command(&mut write_half, &line, &username, rooms.clone(), users.clone()).await;
+                                },
+                                "/capacity" => {
+                                    handle_capacity_command(&mut write_half, &line, &username, tx.clone(), rooms.clone(), users.clone()).await;
+                                },
+                                "/waitlist" => {
+                                    handle_waitlist_command(&mut write_half, &line, &username, rooms.clone()).await;
+                                },
+                                "/room_info" => {
+                                    handle_room_info_command(&mut write_half, &line, &username, rooms.clone()).await;
+                                },
+                                "/invite" => {
+                                    handle_invite_command(&mut write_half, &line, &username, tx.clone(), rooms.clone(), users.clone()).await;
                                 },
-                                "/m_room" => {
-                                    handle_m_room_command(&mut write_half, &line, &username, addr, tx.clone(), rooms.clone()).await;
+                                "/invites" => {
+                                    handle_invites_command(&mut write_half, &username, rooms.clone()).await;
+                                },
+                                "/accept" => {
+                                    handle_accept_command(&mut write_half, &line, &username, addr, rooms.clone(), users.clone(), history.clone()).await;
+                                },
+                                "/decline" => {
+                                    handle_decline_command(&mut write_half, &line, &username, rooms.clone()).await;
+                                },
+                                "/read_only" => {
+                                    handle_read_only_command(&mut write_half, &line, &username, addr, tx.clone(), rooms.clone(), users.clone()).await;
                                 },
                                 "/view_users" => {
-                                    handle_view_users_command(&mut write_half, &line, &username, rooms.clone()).await;
+                                    handle_view_users_command(&mut write_half, &line, &username, rooms.clone(), users.clone()).await;
                                 },
                                 "/view_rooms" => {
-                                    let rooms_guard = rooms.lock().await;
-                                    for room in rooms_guard.iter() {
-                                        write_half
-                                            .write_all(format!("[{}]\n", room.name).as_bytes())
-                                            .await
-                                            .unwrap();
-                                    }
+                                    handle_view_rooms_command(&mut write_half, &line, &username, rooms.clone()).await;
                                 },
                                 "/list" => {
                                     handle_list_command(&mut write_half, users.clone()).await;
@@ -155,51 +965,170 @@ async fn main() {
                                     }
                                 },
                                 "/pm" => {
-                                    let mut parts = line.trim().split_whitespace();
+                                    let mut parts = line.split_whitespace();
                                     parts.next(); // skip /pm
-                                    let recipient = parts.next().unwrap();
+                                    let recipient = parts.next();
                                     let message = parts.collect::<Vec<&str>>().join(" ");
-                                    handle_pm_command(&mut write_half, recipient, &message, &username, tx.clone(), users.clone()).await;
+                                    match recipient.filter(|_| !message.is_empty()) {
+                                        Some(recipient) => {
+                                            handle_pm_command(&mut write_half, recipient, &message, &username, tx.clone(), users.clone(), history.clone()).await;
+                                        },
+                                        None => {
+                                            if write_half.write_all(format!("\n{}[i] Usage: /pm <username> <message>{}\n\n", color_codes::YELLOW, color_codes::RESET).as_bytes()).await.is_err() {
+                                                break;
+                                            }
+                                        }
+                                    }
+                                },
+                                "/gm" => {
+                                    handle_gm_command(&mut write_half, &line, &username, tx.clone(), users.clone(), history.clone()).await;
+                                },
+                                "/gm_add" => {
+                                    handle_gm_add_command(&mut write_half, &line, &username, tx.clone(), users.clone(), history.clone()).await;
+                                },
+                                "/gm_leave" => {
+                                    handle_gm_leave_command(&mut write_half, &line, &username, tx.clone(), users.clone(), history.clone()).await;
+                                },
+                                "/groups" => {
+                                    handle_groups_command(&mut write_half, &username, history.clone()).await;
+                                },
+                                "/edit" => {
+                                    handle_edit_command(&mut write_half, &line, &username, addr, tx.clone(), users.clone(), history.clone()).await;
+                                },
+                                "/delete" => {
+                                    handle_delete_command(&mut write_half, &line, &username, addr, tx.clone(), rooms.clone(), users.clone(), history.clone()).await;
+                                },
+                                "/reply" => {
+                                    handle_reply_command(&mut write_half, &line, &username, addr, tx.clone(), rooms.clone(), users.clone(), profiles.clone(), history.clone()).await;
+                                },
+                                "/thread" => {
+                                    handle_thread_command(&mut write_half, &line, &username, rooms.clone(), history.clone()).await;
+                                },
+                                "/thread_replies" => {
+                                    match words.get(1) {
+                                        Some(&"show") => show_thread_replies = true,
+                                        Some(&"hide") => show_thread_replies = false,
+                                        _ => {
+                                            if write_half.write_all(format!("\n{}[i] Usage: /thread_replies <show|hide>{}\n\n", color_codes::YELLOW, color_codes::RESET).as_bytes()).await.is_err() {
+                                                break;
+                                            }
+                                        }
+                                    }
+                                },
+                                "/react" => {
+                                    handle_react_command(&mut write_half, &line, &username, addr, tx.clone(), rooms.clone(), users.clone(), history.clone()).await;
+                                },
+                                "/unreact" => {
+                                    handle_unreact_command(&mut write_half, &line, &username, addr, tx.clone(), rooms.clone(), users.clone(), history.clone()).await;
+                                },
+                                "/mentions" => {
+                                    handle_mentions_command(&mut write_half, &line, &username, rooms.clone(), history.clone()).await;
+                                },
+                                "/pin" => {
+                                    handle_pin_command(&mut write_half, &line, &username, addr, tx.clone(), rooms.clone(), users.clone(), history.clone()).await;
+                                },
+                                "/unpin" => {
+                                    handle_unpin_command(&mut write_half, &line, &username, addr, tx.clone(), rooms.clone(), users.clone(), history.clone()).await;
+                                },
+                                "/pins" => {
+                                    handle_pins_command(&mut write_half, &line, &username, rooms.clone(), history.clone()).await;
+                                },
+                                "/unread" => {
+                                    handle_unread_command(&mut write_half, &username, rooms.clone(), users.clone(), history.clone()).await;
+                                },
+                                "/catchup" => {
+                                    handle_catchup_command(&mut write_half, &line, &username, rooms.clone(), users.clone(), history.clone()).await;
+                                },
+                                "/search" => {
+                                    handle_search_command(&mut write_half, &line, &username, rooms.clone(), history.clone()).await;
+                                },
+                                "/history" => {
+                                    handle_history_command(&mut write_half, &line, &username, rooms.clone(), history.clone()).await;
+                                },
+                                "/away" => {
+                                    handle_away_command(&mut write_half, &line, &username, users.clone()).await;
+                                },
+                                "/back" => {
+                                    handle_back_command(&mut write_half, &username, users.clone()).await;
+                                },
+                                "/nick" => {
+                                    handle_nick_command(&mut write_half, &line, &username, addr, tx.clone(), users.clone(), profiles.clone()).await;
+                                },
+                                "/profile" => {
+                                    handle_profile_command(&mut write_half, &line, &username, users.clone(), profiles.clone()).await;
+                                },
+                                "/whois" => {
+                                    handle_whois_command(&mut write_half, &line, &username, users.clone(), profiles.clone()).await;
+                                },
+                                "/ping" => {
+                                    let reply = match update_latency(&username, write_half.as_ref(), users.clone()).await {
+                                        Some(latency) => format!("[i] Latency: {}\n", latency::format_latency(latency)),
+                                        None => "[i] Latency can't be measured on this server\n".to_string(),
+                                    };
+                                    if write_half.write_all(reply.as_bytes()).await.is_err() {
+                                        break;
+                                    }
                                 },
                                 "/exit" => {
-                                    handle_user_disconnection(&username, &addr, tx.clone(), users.clone()).await;
                                     break;
                                 },
                                 _ => {
-                                    write_half.write_all(format!("{}\n[i] No such command{}\n\n", color_codes::RED, color_codes::RESET).as_bytes()).await.unwrap();
+                                    if write_half.write_all(format!("{}\n[i] No such command{}\n\n", color_codes::RED, color_codes::RESET).as_bytes()).await.is_err() {
+                                        break;
+                                    }
                                 }
                             };
+                        } else if let Some(fence) = line.trim().strip_prefix("```").filter(|rest| !rest.contains("```")) {
+                            // an opening fence starts a multi-line message to the active room
+                            let language = Some(fence.trim()).filter(|language| !language.is_empty()).map(|language| language.to_string());
+                            paste = Some(Paste::new(&active_room, true, language));
                         } else {
-                            println!("Broadcasting message from {}: {}", username, line);
-                            let msg_with_username = format!("[glb] [{}] {}", username, line);
-                            tx.send((msg_with_username.clone(), addr)).unwrap();
+                            println!("Broadcasting message from {} to {}: {}", username, active_room, line);
+                            send_room_message(&mut write_half, &active_room, line.trim_end(), None, &username, addr, tx.clone(), rooms.clone(), users.clone(), profiles.clone(), history.clone()).await;
+                        }
+
+                        if show_prompt && write_half.write_all(prompt(&active_room).as_bytes()).await.is_err() {
+                            break;
                         }
 
                         line.clear();
                         continue;
 
                     },
-                    result = rx.recv() => {
-                        let (msg, other_addr ) = result.unwrap();
-
-                        // check if incoming message is a private message
-                        // private messages start with [PM]
-
-                        if msg.starts_with("[PM]") {
-                            if addr == other_addr{
-                                write_half.write_all(msg.as_bytes()).await.unwrap();
-                                println!("Msg received by: {}", username);
-                            }
-                            continue;
+                    _ = idle_check.tick() => {
+                        update_latency(&username, write_half.as_ref(), users.clone()).await;
+                        let idle = last_active.elapsed();
+                        if idle >= Duration::from_secs(config::IDLE_DISCONNECT_SECS) {
+                            println!("User {} disconnected after being idle", username);
+                            let _ = write_half.write_all(format!("{}[i] Disconnected after being idle for {} minutes{}\n", color_codes::RED, idle.as_secs() / 60, color_codes::RESET).as_bytes()).await;
+                            break;
                         }
+                        if idle >= Duration::from_secs(config::IDLE_AWAY_SECS) && mark_user_idle_away(&username, users.clone()).await {
+                            let _ = write_half.write_all(format!("{}[i] You have been marked as away after being idle{}\n", color_codes::YELLOW, color_codes::RESET).as_bytes()).await;
+                        }
+                    },
+                    result = rx.recv() => {
+                        let (msg, other_addr ) = match result {
+                            Ok(received) => received,
+                            Err(RecvError::Lagged(skipped)) => {
+                                println!("{} missed {} messages", username, skipped);
+                                continue;
+                            },
+                            Err(RecvError::Closed) => break,
+                        };
 
+                        // set when this session should get the message
+                        let mut deliver = false;
+                        let mut mentioned = false;
+                        // conversation and send time of a message that counts as read once it is shown
+                        let mut read_up_to: Option<(Option<String>, SystemTime)> = None;
 
-                        let msg_type = if msg.starts_with("[PM]") {
+                        // private messages start with [PM], group messages with [GM <id>] and server notices
+                        // to one user with [notice], all are addressed to the recipient
+                        let msg_type = if msg.starts_with("[PM]") || msg.starts_with("[GM ") || msg.starts_with("[notice]") {
                             "PM"
-                        } else if msg.starts_with("[glb]") {
-                            "GLB"
-                        } else if msg.starts_with("[i]") {
-                            "INFO"
+                        } else if msg.starts_with("[event]") {
+                            "EVENT"
                         } else {
                             "ROOM"
                         };
@@ -208,19 +1137,24 @@ async fn main() {
                         match msg_type {
                             "PM" => {
                                 if addr == other_addr {
-                                    write_half.write_all(msg.as_bytes()).await.unwrap();
+                                    deliver = true;
                                     println!("PM received by: {}", username);
+                                    if let Some(id) = msg.split_whitespace().nth(1).and_then(|id| id.strip_prefix('#')).filter(|_| msg.starts_with("[PM]")) {
+                                        read_up_to = history.lock().await.find(id).map(|stored| (None, stored.sent_at));
+                                    }
                                 }
                             },
-                            "GLB" => {
-                                if addr != other_addr {
-                                    write_half.write_all(msg.as_bytes()).await.unwrap();
-                                    println!("Global message received by: {}", username);
-                                }
-                            },
-                            "INFO" => {
-                                if addr != other_addr {
-                                    write_half.write_all(msg.as_bytes()).await.unwrap();
+                            "EVENT" => {
+                                // "[event] <kind> <room_name|PM> ...", private ones are addressed to the peer like PMs
+                                let target = msg.split_whitespace().nth(2).unwrap_or_default();
+                                if !machine_mode {
+                                    deliver = false;
+                                } else if target == "PM" {
+                                    deliver = addr == other_addr;
+                                } else if addr != other_addr {
+                                    let users_guard = users.lock().await;
+                                    deliver = users_guard.iter().any(|u| u.username == username && u.rooms.iter().any(|r| r == target));
+                                    drop(users_guard);
                                 }
                             },
                             "ROOM" => {
@@ -229,51 +1163,362 @@ async fn main() {
                                 let room_name = msg.split_whitespace().next().unwrap().trim_start_matches('[').trim_end_matches(']');
                                 // Check if the user is in the room
                                 let users_guard = users.lock().await;
-                                let user = users_guard.iter().find(|u| u.username == username);
-                                if let Some(user) = user {
-                                    if user.rooms.contains(&room_name.to_string()) {
-                                        write_half.write_all(msg.as_bytes()).await.unwrap();
-                                        println!("Room message received by: {}", username);
+                                deliver = users_guard.iter().any(|u| u.username == username && u.rooms.iter().any(|r| r == room_name));
+                                drop(users_guard);
+                                // the id after the room name was put there by the server, so it can be looked up
+                                if let Some(id) = msg.split_whitespace().nth(1).and_then(|id| id.strip_prefix('#')).filter(|_| deliver) {
+                                    let history_guard = history.lock().await;
+                                    if let Some(stored) = history_guard.find(id) {
+                                        mentioned = stored.mentions.contains(&username);
+                                        if room_name == active_room {
+                                            read_up_to = Some((Some(room_name.to_string()), stored.sent_at));
+                                        }
+                                        if !show_thread_replies && !mentioned {
+                                            deliver = stored.thread.as_deref().is_none_or(|thread| history_guard.find(thread).is_some_and(|root| root.author == username));
+                                        }
                                     }
                                 }
+                                if deliver {
+                                    println!("Room message received by: {}", username);
+                                }
                                 }
                             },
                             _ => {
                                 println!("Unknown message type");
                             }
                         }
+
+                        if deliver {
+                            // ring the bell and highlight messages that mention the user
+                            let msg = if mentioned {
+                                format!("{}{}{}{}\n", color_codes::BELL, color_codes::YELLOW, msg.trim_end(), color_codes::RESET)
+                            } else {
+                                msg
+                            };
+                            // overwrite the prompt with the message and draw it again below
+                            let output = if show_prompt {
+                                format!("\r{}{}", msg, prompt(&active_room))
+                            } else {
+                                msg
+                            };
+                            if write_half.write_all(output.as_bytes()).await.is_err() {
+                                break;
+                            }
+                            if let Some((room_name, sent_at)) = read_up_to {
+                                mark_read(&username, room_name.as_deref(), sent_at, rooms.clone(), users.clone()).await;
+                            }
+                        }
                     }
                 }
             }
+
+            // every way out of the session ends here, including failed writes to a peer that is gone,
+            // so nobody is left behind as a ghost in users or in a room
+            handle_user_disconnection(&username, &addr, tx.clone(), users.clone(), rooms.clone(), history.clone(), uploads.clone()).await;
         });
     }
 }
 
 async fn ask_for_username(socket: &mut tokio::net::TcpStream) -> Result<String, std::io::Error> {
-    let mut username = String::new();
-
     // Send a message asking for username
     socket.write_all(b"Please enter your username: ").await?;
 
-    // Read the username from the client
-    let mut reader = BufReader::new(socket);
-    reader.read_line(&mut username).await?;
+    // Read the username from the client, limited like every other line so a peer can't
+    // stream a name without a newline until the login times out
+    let mut reader = BufReader::new(socket).take(config::MAX_LINE_LENGTH as u64 + 1);
+    let mut line = vec![];
+    if reader.read_until(b'\n', &mut line).await? == 0 {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    if line.len() > config::MAX_LINE_LENGTH {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "username line too long"));
+    }
+    let username = String::from_utf8(line).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
 
     Ok(username.trim().to_string())
 }
 
+// usernames are typed after /pm and shown as [name] in every message, so they are a single word without brackets
+fn is_valid_username(username: &str) -> bool {
+    !username.is_empty()
+        && username.len() <= config::MAX_USERNAME_LENGTH
+        && !username.contains(|c: char| c.is_whitespace() || c.is_control() || c == '[' || c == ']')
+}
+
+// a new login can't take over a connected username or the nick someone online is going by
+fn username_taken(username: &str, users: &[UserInfo]) -> bool {
+    users.iter().any(|u| {
+        u.username == username || u.nick.as_deref().is_some_and(|nick| nick.eq_ignore_ascii_case(username))
+    })
+}
+
 async fn handle_user_disconnection(
     username: &str,
     addr: &std::net::SocketAddr,
     tx: broadcast::Sender<(String, std::net::SocketAddr)>,
     users: Arc<TokioMutex<Vec<UserInfo>>>,
+    rooms: Arc<TokioMutex<Vec<Room>>>,
+    history: Arc<TokioMutex<History>>,
+    uploads: Arc<TokioMutex<Uploads>>,
 ) {
     println!("{} disconnected", username);
-    let dc_message = format!("[i] {} disconnected\n", username);
-    tx.send((dc_message.clone(), *addr)).unwrap();
+
+    // remove disconnected user from every room they were in and let those rooms know,
+    // this includes the global room so users who left it don't hear about disconnects
+    let mut rooms_guard = rooms.lock().await;
+    for room in rooms_guard.iter_mut() {
+        room.waitlist.retain(|u| u != username);
+        if !room.users.iter().any(|u| u.username == username) {
+            continue;
+        }
+        room.users.retain(|u| u.username != username);
+        if config::KEEP_ROOMS_ON_DISCONNECT && !room.offline_members.iter().any(|u| u == username) {
+            room.offline_members.push(username.to_string());
+        }
+        let leave_message = format!("[{}] [i] {} left the room (disconnected)\n", room.name, username);
+        tx.send((leave_message, *addr)).unwrap();
+        admit_from_waitlist(room, tx.clone(), users.clone()).await;
+    }
+    let (abandoned, kept): (Vec<Room>, Vec<Room>) = rooms_guard.drain(..).partition(|r| r.is_abandoned());
+    *rooms_guard = kept;
+    for room in abandoned {
+        println!("Room {} deleted, its last member left", room.name);
+        close_room(room, "its last member left", tx.clone(), users.clone(), history.clone(), uploads.clone()).await;
+    }
+    drop(rooms_guard);
 
     // remove disconnected user from the list
     let mut users_guard = users.lock().await;
     users_guard.retain(|u| u.username != username);
     drop(users_guard);
 }
+
+async fn sweep_expired_rooms(
+    tx: broadcast::Sender<(String, std::net::SocketAddr)>,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+    rooms: Arc<TokioMutex<Vec<Room>>>,
+    history: Arc<TokioMutex<History>>,
+    uploads: Arc<TokioMutex<Uploads>>,
+) {
+    let mut rooms_guard = rooms.lock().await;
+    let (expired, kept): (Vec<Room>, Vec<Room>) = rooms_guard.drain(..).partition(|r| r.is_expired());
+    *rooms_guard = kept;
+    for room in expired {
+        println!("Room {} deleted after being inactive", room.name);
+        let reason = format!("it was inactive for {}", format_duration(room.last_activity.elapsed()));
+        close_room(room, &reason, tx.clone(), users.clone(), history.clone(), uploads.clone()).await;
+    }
+    drop(rooms_guard);
+}
+
+// multi-line message being collected with /paste or a ``` fence
+#[derive(Debug)]
+struct Paste {
+    room: String,
+    lines: Vec<String>,
+    bytes: usize,
+    // closed by another ``` line instead of /end
+    fenced: bool,
+    // language given after the opening fence, e.g. ```rust
+    language: Option<String>,
+    // too long or sent too fast, the remaining lines are discarded up to the closing line
+    dropped: bool,
+}
+
+impl Paste {
+    fn new(room: &str, fenced: bool, language: Option<String>) -> Self {
+        Paste {
+            room: room.to_string(),
+            lines: vec![],
+            bytes: 0,
+            fenced,
+            language,
+            dropped: false,
+        }
+    }
+
+    // one message with every line indented the same way so it stands out from other chat
+    fn render(&self) -> String {
+        let mut message = match &self.language {
+            Some(language) => format!("pasted {} lines ({}):", self.lines.len(), language),
+            None => format!("pasted {} lines:", self.lines.len()),
+        };
+        for paste_line in self.lines.iter() {
+            message.push_str("\n  | ");
+            message.push_str(paste_line);
+        }
+        message
+    }
+}
+
+// shown before the user's input when /prompt is on
+fn prompt(active_room: &str) -> String {
+    format!("({})> ", active_room)
+}
+
+// display name for a connected user, falls back to the username
+async fn display_name(username: &str, users: Arc<TokioMutex<Vec<UserInfo>>>) -> String {
+    let users_guard = users.lock().await;
+    users_guard
+        .iter()
+        .find(|u| u.username == username)
+        .map(|u| u.display_name().to_string())
+        .unwrap_or_else(|| username.to_string())
+}
+
+// record activity for a user; returns true if this cleared an automatic away status
+async fn mark_user_active(username: &str, users: Arc<TokioMutex<Vec<UserInfo>>>) -> bool {
+    let mut users_guard = users.lock().await;
+    let mut cleared_away = false;
+    if let Some(user) = users_guard.iter_mut().find(|u| u.username == username) {
+        user.last_active = Instant::now();
+        if user.away.as_ref().is_some_and(|away| away.automatic) {
+            user.away = None;
+            cleared_away = true;
+        }
+    }
+    cleared_away
+}
+
+// store the connection's current round trip time for /whois, returns it if it could be measured
+async fn update_latency(
+    username: &str,
+    socket: &tokio::net::TcpStream,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+) -> Option<Duration> {
+    let latency = latency::round_trip_time(socket)?;
+    let mut users_guard = users.lock().await;
+    if let Some(user) = users_guard.iter_mut().find(|u| u.username == username) {
+        user.latency = Some(latency);
+    }
+    Some(latency)
+}
+
+// mark an idle user as away unless they already are; returns true if the status changed
+async fn mark_user_idle_away(username: &str, users: Arc<TokioMutex<Vec<UserInfo>>>) -> bool {
+    let mut users_guard = users.lock().await;
+    if let Some(user) = users_guard.iter_mut().find(|u| u.username == username) {
+        if user.away.is_none() {
+            user.away = Some(Away {
+                message: "Idle".to_string(),
+                since: Instant::now(),
+                automatic: true,
+            });
+            return true;
+        }
+    }
+    false
+}
+
+// moves the user's read marker of a room, or of private messages when there is no room, unless they are away
+async fn mark_read(
+    username: &str,
+    room_name: Option<&str>,
+    sent_at: SystemTime,
+    rooms: Arc<TokioMutex<Vec<Room>>>,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+) {
+    let mut users_guard = users.lock().await;
+    let Some(user) = users_guard.iter_mut().find(|u| u.username == username) else {
+        return;
+    };
+    if user.away.is_some() {
+        return;
+    }
+    if room_name.is_none() {
+        user.pms_read_at = user.pms_read_at.max(sent_at);
+    }
+    drop(users_guard);
+
+    if let Some(room_name) = room_name {
+        let mut rooms_guard = rooms.lock().await;
+        if let Some(room) = rooms_guard.iter_mut().find(|r| r.name == room_name) {
+            room.mark_read(username, sent_at);
+        }
+    }
+}
+
+// put a reconnecting user back into the rooms they were kept in while offline, rooms that filled up
+// in the meantime put them on the waitlist instead; returns a line to show for each room
+async fn restore_room_memberships(
+    username: &str,
+    addr: std::net::SocketAddr,
+    rooms: Arc<TokioMutex<Vec<Room>>>,
+    users: Arc<TokioMutex<Vec<UserInfo>>>,
+) -> Vec<String> {
+    let mut rejoined_rooms = vec![];
+    let mut notices = vec![];
+
+    let mut rooms_guard = rooms.lock().await;
+    for room in rooms_guard.iter_mut() {
+        if !room.offline_members.iter().any(|u| u == username) {
+            continue;
+        }
+        room.offline_members.retain(|u| u != username);
+        if room.is_full() {
+            if !room.waitlist.iter().any(|u| u == username) {
+                room.waitlist.push(username.to_string());
+            }
+            let position = room.waitlist.iter().position(|u| u == username).unwrap() + 1;
+            notices.push(format!("Room {} is full, you are number {} on its waitlist\n", room.name, position));
+            continue;
+        }
+        notices.push(format!("You rejoined room {}\n", room.name));
+        let mut member = UserInfo::new(username, addr);
+        member.rooms.push(room.name.clone());
+        room.users.push(member);
+        rejoined_rooms.push(room.name.clone());
+    }
+    drop(rooms_guard);
+
+    let mut users_guard = users.lock().await;
+    if let Some(user) = users_guard.iter_mut().find(|u| u.username == username) {
+        user.rooms.extend(rejoined_rooms);
+    }
+    drop(users_guard);
+
+    notices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usernames_are_one_word_without_brackets() {
+        for username in ["alice", "bob_2", "zoë", &"a".repeat(config::MAX_USERNAME_LENGTH)] {
+            assert!(is_valid_username(username), "{}", username);
+        }
+        for username in ["", "alice smith", "tab\there", "[PM]", "a]b", "bell\u{7}", &"a".repeat(config::MAX_USERNAME_LENGTH + 1)] {
+            assert!(!is_valid_username(username), "{:?}", username);
+        }
+    }
+
+    #[test]
+    fn parsed_dates_format_back_to_the_same_day() {
+        for date in ["1970-01-01", "1999-12-31", "2000-02-29", "2024-02-29", "2024-03-01", "2100-12-31", "9999-12-31"] {
+            let time = parse_date(date).unwrap();
+            assert_eq!(format_timestamp(time), format!("{} 00:00 UTC", date));
+        }
+    }
+
+    #[test]
+    fn timestamps_keep_the_time_of_day() {
+        let time = parse_date("2024-06-15").unwrap() + Duration::from_secs(13 * 3600 + 7 * 60 + 59);
+        assert_eq!(format_timestamp(time), "2024-06-15 13:07 UTC");
+    }
+
+    #[test]
+    fn dates_that_dont_exist_are_rejected() {
+        for date in ["2023-02-29", "1900-02-29", "2024-04-31", "2024-13-01", "2024-00-10", "2024-01-00", "2024-01-32"] {
+            assert_eq!(parse_date(date), None, "{}", date);
+        }
+    }
+
+    #[test]
+    fn malformed_dates_are_rejected() {
+        for date in ["", "2024", "2024-01", "2024-1-x", "yesterday", "2024/01/01", "1969-12-31", "10000-01-01", "100000000000000-01-01", "9223372036854775807-01-01"] {
+            assert_eq!(parse_date(date), None, "{}", date);
+        }
+    }
+}
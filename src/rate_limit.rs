@@ -0,0 +1,245 @@
+use crate::config::{self, RateLimits};
+use std::time::{Duration, Instant};
+
+// What the session loop should do with a line after it went through the limiter
+#[derive(Debug, PartialEq)]
+pub(crate) enum Verdict {
+    Allow,
+    Warn,
+    Throttle,
+    Mute(Duration),
+    Disconnect,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    capacity: f64,
+    refill_per_sec: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(refill_per_sec: f64, capacity: f64) -> Self {
+        TokenBucket {
+            capacity,
+            refill_per_sec,
+            tokens: capacity,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    fn has(&self, amount: f64) -> bool {
+        self.tokens >= amount
+    }
+
+    fn take(&mut self, amount: f64) {
+        self.tokens -= amount;
+    }
+}
+
+// Per-session limiter, one bucket each for chat lines, commands, raw bytes, upload bytes and paste lines
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    messages: TokenBucket,
+    commands: TokenBucket,
+    bytes: TokenBucket,
+    uploads: TokenBucket,
+    paste_lines: TokenBucket,
+    strikes: u32,
+    last_strike: Option<Instant>,
+    muted_until: Option<Instant>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limits: &RateLimits) -> Self {
+        RateLimiter {
+            messages: TokenBucket::new(limits.messages_per_sec, limits.message_burst),
+            commands: TokenBucket::new(limits.commands_per_sec, limits.command_burst),
+            bytes: TokenBucket::new(limits.bytes_per_sec, limits.byte_burst),
+            uploads: TokenBucket::new(limits.upload_bytes_per_sec, limits.upload_byte_burst),
+            paste_lines: TokenBucket::new(limits.paste_lines_per_sec, limits.paste_line_burst),
+            strikes: 0,
+            last_strike: None,
+            muted_until: None,
+        }
+    }
+
+    pub(crate) fn check(&mut self, is_command: bool, len: usize) -> Verdict {
+        let now = Instant::now();
+        if self.is_muted(now) {
+            return self.strike(now);
+        }
+
+        self.messages.refill(now);
+        self.commands.refill(now);
+        self.bytes.refill(now);
+
+        let bucket = if is_command {
+            &mut self.commands
+        } else {
+            &mut self.messages
+        };
+
+        // a message bigger than the byte bucket (e.g. a long paste) only needs a full bucket
+        let len = (len as f64).min(self.bytes.capacity);
+        if bucket.has(1.0) && self.bytes.has(len) {
+            bucket.take(1.0);
+            self.bytes.take(len);
+            return Verdict::Allow;
+        }
+
+        self.strike(now)
+    }
+
+    // a line of a file being uploaded, cancelling an upload doesn't give its bytes back
+    pub(crate) fn check_upload(&mut self, len: usize) -> Verdict {
+        self.check_bucket(|limiter| &mut limiter.uploads, len as f64)
+    }
+
+    // a line of a multi-line message, kept or thrown away
+    pub(crate) fn check_paste_line(&mut self) -> Verdict {
+        self.check_bucket(|limiter| &mut limiter.paste_lines, 1.0)
+    }
+
+    fn check_bucket(&mut self, bucket: fn(&mut RateLimiter) -> &mut TokenBucket, amount: f64) -> Verdict {
+        let now = Instant::now();
+        if self.is_muted(now) {
+            return self.strike(now);
+        }
+
+        let bucket = bucket(self);
+        bucket.refill(now);
+        if bucket.has(amount) {
+            bucket.take(amount);
+            return Verdict::Allow;
+        }
+
+        self.strike(now)
+    }
+
+    fn is_muted(&mut self, now: Instant) -> bool {
+        // forget old strikes once the user has behaved for a while
+        if let Some(last_strike) = self.last_strike {
+            if now.duration_since(last_strike) >= Duration::from_secs(config::STRIKE_RESET_SECS) {
+                self.strikes = 0;
+                self.last_strike = None;
+            }
+        }
+
+        match self.muted_until {
+            Some(muted_until) if now < muted_until => true,
+            _ => {
+                self.muted_until = None;
+                false
+            }
+        }
+    }
+
+    // escalate: warn on the first strike, then drop lines, then mute, then disconnect
+    fn strike(&mut self, now: Instant) -> Verdict {
+        self.strikes += 1;
+        self.last_strike = Some(now);
+
+        if self.strikes >= config::DISCONNECT_STRIKES {
+            Verdict::Disconnect
+        } else if self.strikes == config::MUTE_STRIKES {
+            let duration = Duration::from_secs(config::MUTE_SECS);
+            self.muted_until = Some(now + duration);
+            Verdict::Mute(duration)
+        } else if self.strikes == 1 {
+            Verdict::Warn
+        } else {
+            Verdict::Throttle
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // buckets that never refill, so the verdicts don't depend on how fast the test runs
+    const LIMITS: RateLimits = RateLimits {
+        messages_per_sec: 0.0,
+        message_burst: 3.0,
+        commands_per_sec: 0.0,
+        command_burst: 3.0,
+        bytes_per_sec: 0.0,
+        byte_burst: 100.0,
+        upload_bytes_per_sec: 0.0,
+        upload_byte_burst: 1000.0,
+        paste_lines_per_sec: 0.0,
+        paste_line_burst: 3.0,
+    };
+
+    #[test]
+    fn escalates_from_warning_to_disconnect() {
+        let mut limiter = RateLimiter::new(&LIMITS);
+        for _ in 0..3 {
+            assert_eq!(limiter.check(false, 1), Verdict::Allow);
+        }
+
+        assert_eq!(limiter.check(false, 1), Verdict::Warn);
+        for _ in 2..config::MUTE_STRIKES {
+            assert_eq!(limiter.check(false, 1), Verdict::Throttle);
+        }
+        assert_eq!(limiter.check(false, 1), Verdict::Mute(Duration::from_secs(config::MUTE_SECS)));
+        // lines sent while muted keep adding strikes
+        for _ in config::MUTE_STRIKES + 1..config::DISCONNECT_STRIKES {
+            assert_eq!(limiter.check(false, 1), Verdict::Throttle);
+        }
+        assert_eq!(limiter.check(false, 1), Verdict::Disconnect);
+    }
+
+    #[test]
+    fn commands_and_messages_have_separate_buckets() {
+        let mut limiter = RateLimiter::new(&LIMITS);
+        for _ in 0..3 {
+            assert_eq!(limiter.check(false, 1), Verdict::Allow);
+        }
+        assert_eq!(limiter.check(true, 1), Verdict::Allow);
+    }
+
+    #[test]
+    fn bytes_are_limited_across_lines() {
+        let mut limiter = RateLimiter::new(&LIMITS);
+        assert_eq!(limiter.check(false, 60), Verdict::Allow);
+        assert_eq!(limiter.check(false, 60), Verdict::Warn);
+    }
+
+    #[test]
+    fn uploads_have_their_own_byte_bucket() {
+        let mut limiter = RateLimiter::new(&LIMITS);
+        assert_eq!(limiter.check_upload(600), Verdict::Allow);
+        assert_eq!(limiter.check(false, 60), Verdict::Allow);
+        assert_eq!(limiter.check_upload(600), Verdict::Warn);
+    }
+
+    #[test]
+    fn paste_lines_have_their_own_bucket() {
+        let mut limiter = RateLimiter::new(&LIMITS);
+        for _ in 0..3 {
+            assert_eq!(limiter.check_paste_line(), Verdict::Allow);
+        }
+        assert_eq!(limiter.check(false, 1), Verdict::Allow);
+        assert_eq!(limiter.check_paste_line(), Verdict::Warn);
+        assert_eq!(limiter.check_paste_line(), Verdict::Throttle);
+    }
+
+    #[test]
+    fn muted_users_cant_upload() {
+        let mut limiter = RateLimiter::new(&LIMITS);
+        for _ in 0..3 + config::MUTE_STRIKES - 1 {
+            limiter.check(false, 1);
+        }
+        assert_eq!(limiter.check(false, 1), Verdict::Mute(Duration::from_secs(config::MUTE_SECS)));
+        assert_eq!(limiter.check_upload(1), Verdict::Throttle);
+    }
+}
@@ -0,0 +1,191 @@
+use crate::config;
+use crate::history::{self, Conversation};
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+// Where a file is shared, groups can't be given files
+#[derive(Debug, Clone)]
+pub(crate) enum UploadTarget {
+    Room(String),
+    User(String),
+}
+
+impl UploadTarget {
+    pub(crate) fn conversation(&self, uploader: &str) -> Conversation {
+        match self {
+            UploadTarget::Room(room_name) => Conversation::Room(room_name.clone()),
+            UploadTarget::User(recipient) => Conversation::Private(uploader.to_string(), recipient.clone()),
+        }
+    }
+}
+
+// File being received with /upload, the base64 lines are collected until /end
+#[derive(Debug)]
+pub(crate) struct Upload {
+    pub(crate) name: String,
+    pub(crate) target: UploadTarget,
+    pub(crate) encoded: String,
+    // too large or sent too fast, the rest of its lines are thrown away until /end or /cancel
+    pub(crate) dropped: bool,
+}
+
+impl Upload {
+    pub(crate) fn new(name: &str, target: UploadTarget) -> Self {
+        Upload {
+            name: name.to_string(),
+            target,
+            encoded: String::new(),
+            dropped: false,
+        }
+    }
+}
+
+// File stored on disk, only the people in its conversation can download it
+#[derive(Debug)]
+pub(crate) struct SharedFile {
+    pub(crate) id: String,
+    pub(crate) name: String,
+    pub(crate) uploader: String,
+    pub(crate) conversation: Conversation,
+    pub(crate) size: usize,
+    pub(crate) uploaded_at: SystemTime,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Uploads {
+    files: Vec<SharedFile>,
+    next_id: u64,
+    // bytes of every stored file, capped at MAX_UPLOAD_STORAGE_BYTES
+    stored_bytes: usize,
+}
+
+impl Uploads {
+    pub(crate) fn has_room_for(&self, size: usize) -> bool {
+        self.stored_bytes + size <= config::MAX_UPLOAD_STORAGE_BYTES
+    }
+
+    // the id to store the next file under, the file only counts once it is added
+    pub(crate) fn next_id(&mut self) -> String {
+        self.next_id += 1;
+        format!("f{}", history::short_id(self.next_id))
+    }
+
+    pub(crate) fn add(&mut self, file: SharedFile) {
+        self.stored_bytes += file.size;
+        self.files.push(file);
+    }
+
+    pub(crate) fn find(&self, id: &str) -> Option<&SharedFile> {
+        self.files.iter().find(|f| f.id == id)
+    }
+
+    // forget a deleted room's files and return their ids so they can be removed from disk
+    pub(crate) fn remove_room(&mut self, room_name: &str) -> Vec<String> {
+        let (removed, kept): (Vec<SharedFile>, Vec<SharedFile>) = std::mem::take(&mut self.files)
+            .into_iter()
+            .partition(|f| matches!(&f.conversation, Conversation::Room(name) if name == room_name));
+        self.files = kept;
+        self.stored_bytes -= removed.iter().map(|f| f.size).sum::<usize>();
+        removed.into_iter().map(|f| f.id).collect()
+    }
+}
+
+// files are stored under their id, the name given by the uploader never touches the path
+pub(crate) fn path(id: &str) -> PathBuf {
+    PathBuf::from(config::UPLOAD_DIR).join(id)
+}
+
+// plain file names with an allowed extension, e.g. build.log
+pub(crate) fn is_valid_name(name: &str) -> bool {
+    let extension = name.rsplit_once('.').map(|(_, extension)| extension.to_ascii_lowercase());
+    name.len() <= config::MAX_UPLOAD_NAME_LENGTH
+        && !name.starts_with('.')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-')
+        && extension.is_some_and(|extension| config::UPLOAD_EXTENSIONS.contains(&extension.as_str()))
+}
+
+const BASE64_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// standard base64 with padding, there is no base64 crate in the dependencies
+pub(crate) fn encode_base64(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = (u32::from(chunk[0]) << 16)
+            | (u32::from(*chunk.get(1).unwrap_or(&0)) << 8)
+            | u32::from(*chunk.get(2).unwrap_or(&0));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64_ALPHABET[((n >> (18 - 6 * i)) & 63) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+// None for anything that isn't valid base64
+pub(crate) fn decode_base64(encoded: &str) -> Option<Vec<u8>> {
+    let data = encoded.trim_end_matches('=');
+    let padding = encoded.len() - data.len();
+    // padding is optional, but when it is there it has to fill up the last group of four
+    if padding > 2 || data.len() % 4 == 1 || (padding > 0 && !encoded.len().is_multiple_of(4)) {
+        return None;
+    }
+    let mut bytes = Vec::with_capacity(data.len() / 4 * 3);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for c in data.bytes() {
+        let value = BASE64_ALPHABET.iter().position(|&b| b == c)? as u32;
+        buffer = (buffer << 6) | value;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_with_padding() {
+        let cases = [("", ""), ("f", "Zg=="), ("fo", "Zm8="), ("foo", "Zm9v"), ("foob", "Zm9vYg=="), ("fooba", "Zm9vYmE=")];
+        for (plain, encoded) in cases {
+            assert_eq!(encode_base64(plain.as_bytes()), encoded);
+            assert_eq!(decode_base64(encoded).unwrap(), plain.as_bytes());
+        }
+    }
+
+    #[test]
+    fn binary_data_round_trips() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        for len in 0..bytes.len() {
+            assert_eq!(decode_base64(&encode_base64(&bytes[..len])).unwrap(), &bytes[..len]);
+        }
+    }
+
+    #[test]
+    fn padding_is_optional() {
+        assert_eq!(decode_base64("Zg").unwrap(), b"f");
+        assert_eq!(decode_base64("Zm8").unwrap(), b"fo");
+    }
+
+    #[test]
+    fn rejects_bad_padding() {
+        for encoded in ["abc===", "a===", "Zg=", "Zm8==", "====", "Z", "Zm9vY", "Zg==Zg=="] {
+            assert_eq!(decode_base64(encoded), None, "{}", encoded);
+        }
+    }
+
+    #[test]
+    fn rejects_characters_outside_the_alphabet() {
+        for encoded in ["Zm9v!", "Zm 9v", "Zm-v", "Zm9v\n"] {
+            assert_eq!(decode_base64(encoded), None, "{:?}", encoded);
+        }
+    }
+}